@@ -0,0 +1,80 @@
+//! Conversion of the history into a polars `DataFrame`, enabled by the
+//! `polars` feature.
+
+use crate::db::SpotifyAnalytics;
+use crate::entry::SpotifyHistoryEntry;
+use crate::error::Result;
+use polars::prelude::*;
+
+impl SpotifyAnalytics {
+    /// Returns the history as a `DataFrame` with one column per
+    /// [`SpotifyHistoryEntry`] field. `ts` is a UTC millisecond datetime and
+    /// missing values are nulls.
+    pub fn to_dataframe(&self) -> Result<DataFrame> {
+        history_to_dataframe(self.history())
+    }
+}
+
+pub(crate) fn history_to_dataframe(history: &[SpotifyHistoryEntry]) -> Result<DataFrame> {
+    type Entry = SpotifyHistoryEntry;
+    let text = |name: &str, f: fn(&Entry) -> &Option<String>| {
+        Column::new(
+            name.into(),
+            history.iter().map(|x| f(x).as_deref()).collect::<Vec<_>>(),
+        )
+    };
+    let flag = |name: &str, f: fn(&Entry) -> Option<bool>| {
+        Column::new(name.into(), history.iter().map(f).collect::<Vec<_>>())
+    };
+
+    let ts = Column::new(
+        "ts".into(),
+        history
+            .iter()
+            .map(|x| x.ts.timestamp_millis())
+            .collect::<Vec<_>>(),
+    )
+    .cast(&DataType::Datetime(
+        TimeUnit::Milliseconds,
+        Some(TimeZone::UTC),
+    ))?;
+    let columns = vec![
+        ts,
+        text("username", |x| &x.username),
+        text("platform", |x| &x.platform),
+        Column::new(
+            "ms_played".into(),
+            history.iter().map(|x| x.ms_played).collect::<Vec<_>>(),
+        ),
+        text("conn_country", |x| &x.conn_country),
+        text("ip_addr_decrypted", |x| &x.ip_addr_decrypted),
+        text("user_agent_decrypted", |x| &x.user_agent_decrypted),
+        text("master_metadata_track_name", |x| {
+            &x.master_metadata_track_name
+        }),
+        text("master_metadata_album_artist_name", |x| {
+            &x.master_metadata_album_artist_name
+        }),
+        text("master_metadata_album_album_name", |x| {
+            &x.master_metadata_album_album_name
+        }),
+        text("spotify_track_uri", |x| &x.spotify_track_uri),
+        text("episode_name", |x| &x.episode_name),
+        text("episode_show_name", |x| &x.episode_show_name),
+        text("spotify_episode_uri", |x| &x.spotify_episode_uri),
+        text("reason_start", |x| &x.reason_start),
+        text("reason_end", |x| &x.reason_end),
+        flag("shuffle", |x| x.shuffle),
+        flag("skipped", |x| x.skipped),
+        flag("offline", |x| x.offline),
+        Column::new(
+            "offline_timestamp".into(),
+            history
+                .iter()
+                .map(|x| x.offline_timestamp)
+                .collect::<Vec<_>>(),
+        ),
+        flag("incognito_mode", |x| x.incognito_mode),
+    ];
+    Ok(DataFrame::new(history.len(), columns)?)
+}
@@ -1,18 +1,30 @@
-use chrono::{DateTime, Utc};
-use color_eyre::eyre::{Context, Result};
-use rusqlite::Connection;
+use crate::entry::{
+    decompressed, read_history, read_history_file, read_video_history, read_video_history_file,
+    SpotifyHistoryEntry, SpotifyVideoHistoryEntry,
+};
+use crate::error::{AnalyticsError, Result};
+use chrono::{DateTime, Datelike, Duration, NaiveDate, TimeZone, Timelike, Utc, Weekday};
+use flate2::read::GzDecoder;
+use indicatif::{ProgressBar, ProgressStyle};
+use rayon::prelude::*;
+use rusqlite::types::ValueRef;
+use rusqlite::{Connection, ErrorCode, OpenFlags, OptionalExtension, Transaction};
 use rusqlite_migration::{Migrations, M};
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt::Debug;
-use std::fs;
-use std::path::Path;
-use tracing::{info, instrument};
+use std::fs::{self, File};
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::time::Duration as StdDuration;
+use tar::Archive;
+use tracing::{info, instrument, warn};
+use zip::ZipArchive;
 
-
-fn get_db() -> Result<Connection> {
-    let migrations = Migrations::new(vec![M::up(
-        "CREATE TABLE spotify_history (
+fn migrations() -> Migrations<'static> {
+    Migrations::new(vec![
+        M::up(
+            "CREATE TABLE spotify_history (
             ts DATETIME NOT NULL,
             username TEXT,
             platform TEXT,
@@ -35,45 +47,807 @@ fn get_db() -> Result<Connection> {
             offline_timestamp UNSIGNED BIG INT,
             incognito_mode BOOLEAN
           );",
+        )
+        .down("DROP TABLE spotify_history;"),
+        // NULLs are distinct in a UNIQUE index, so the nullable columns are
+        // coalesced to make podcast and music rows deduplicate alike.
+        M::up(
+            "DELETE FROM spotify_history WHERE rowid NOT IN (
+                SELECT MIN(rowid) FROM spotify_history GROUP BY
+                    ts,
+                    IFNULL(spotify_track_uri, ''),
+                    IFNULL(spotify_episode_uri, ''),
+                    ms_played,
+                    IFNULL(username, '')
+            );
+            CREATE UNIQUE INDEX spotify_history_unique_play ON spotify_history (
+                ts,
+                IFNULL(spotify_track_uri, ''),
+                IFNULL(spotify_episode_uri, ''),
+                ms_played,
+                IFNULL(username, '')
+            );",
+        )
+        .down("DROP INDEX spotify_history_unique_play;"),
+        M::up(
+            "CREATE INDEX spotify_history_artist ON spotify_history (master_metadata_album_artist_name);
+            CREATE INDEX spotify_history_album ON spotify_history (master_metadata_album_album_name);
+            CREATE INDEX spotify_history_show ON spotify_history (episode_show_name);
+            CREATE INDEX spotify_history_ts ON spotify_history (ts);",
+        )
+        .down(
+            "DROP INDEX spotify_history_artist;
+            DROP INDEX spotify_history_album;
+            DROP INDEX spotify_history_show;
+            DROP INDEX spotify_history_ts;",
+        ),
+        M::up(
+            "CREATE TABLE import_meta (
+                id INTEGER PRIMARY KEY CHECK (id = 1),
+                last_import_ts DATETIME NOT NULL
+            );",
+        )
+        .down("DROP TABLE import_meta;"),
+        M::up(
+            "CREATE TABLE track_duration (
+                spotify_track_uri TEXT PRIMARY KEY,
+                duration_ms INTEGER NOT NULL
+            );",
+        )
+        .down("DROP TABLE track_duration;"),
+        // Live views, so there is nothing to refresh after an import.
+        M::up(
+            "CREATE VIEW v_top_artists AS
+                SELECT master_metadata_album_artist_name AS artist, SUM(ms_played) AS ms_played
+                FROM spotify_history
+                WHERE master_metadata_album_artist_name IS NOT NULL
+                GROUP BY master_metadata_album_artist_name;
+            CREATE VIEW v_top_tracks AS
+                SELECT master_metadata_track_name AS track,
+                    master_metadata_album_artist_name AS artist,
+                    SUM(ms_played) AS ms_played
+                FROM spotify_history
+                WHERE master_metadata_track_name IS NOT NULL
+                    AND master_metadata_album_artist_name IS NOT NULL
+                GROUP BY master_metadata_track_name, master_metadata_album_artist_name;
+            CREATE VIEW v_top_albums AS
+                SELECT master_metadata_album_album_name AS album,
+                    master_metadata_album_artist_name AS artist,
+                    SUM(ms_played) AS ms_played
+                FROM spotify_history
+                WHERE master_metadata_album_album_name IS NOT NULL
+                    AND master_metadata_album_artist_name IS NOT NULL
+                GROUP BY master_metadata_album_album_name, master_metadata_album_artist_name;
+            CREATE VIEW v_top_shows AS
+                SELECT episode_show_name AS show, SUM(ms_played) AS ms_played
+                FROM spotify_history
+                WHERE episode_show_name IS NOT NULL
+                GROUP BY episode_show_name;",
+        )
+        .down(
+            "DROP VIEW v_top_artists;
+            DROP VIEW v_top_tracks;
+            DROP VIEW v_top_albums;
+            DROP VIEW v_top_shows;",
+        ),
+        M::up(
+            "CREATE TABLE video_history (
+                ts DATETIME NOT NULL,
+                username TEXT,
+                platform TEXT,
+                ms_played UNSIGNED BIG INT,
+                conn_country TEXT,
+                episode_name TEXT,
+                episode_show_name TEXT,
+                spotify_episode_uri TEXT,
+                reason_start TEXT,
+                reason_end TEXT,
+                shuffle BOOLEAN,
+                skipped BOOLEAN,
+                offline BOOLEAN,
+                incognito_mode BOOLEAN
+            );
+            CREATE UNIQUE INDEX video_history_unique_play ON video_history (
+                ts,
+                IFNULL(spotify_episode_uri, ''),
+                ms_played,
+                IFNULL(username, '')
+            );",
+        )
+        .down("DROP TABLE video_history;"),
+        M::up(
+            "CREATE TABLE annotations (
+                label TEXT NOT NULL,
+                from_ts DATETIME NOT NULL,
+                to_ts DATETIME NOT NULL
+            );",
+        )
+        .down("DROP TABLE annotations;"),
+        // Tracks the Web API no longer knows, so lookups don't retry them.
+        M::up(
+            "CREATE TABLE track_unavailable (
+                spotify_track_uri TEXT PRIMARY KEY
+            );",
+        )
+        .down("DROP TABLE track_unavailable;"),
+    ])
+}
+
+/// How long connections wait on a database locked by another process unless
+/// given a timeout with [`get_db_with_busy_timeout`].
+pub const DEFAULT_BUSY_TIMEOUT_SECS: u64 = 5;
+
+fn is_busy(err: &rusqlite::Error) -> bool {
+    matches!(
+        err.sqlite_error_code(),
+        Some(ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked)
     )
-    .down("DROP TABLE spotify_history;")]);
+}
+
+/// Opens the database at `path`, creating it if needed, and migrates it to the
+/// latest schema, waiting [`DEFAULT_BUSY_TIMEOUT_SECS`] on a locked database.
+pub fn get_db(path: &Path) -> Result<Connection> {
+    get_db_with_busy_timeout(path, StdDuration::from_secs(DEFAULT_BUSY_TIMEOUT_SECS))
+}
+
+/// Like [`get_db`], but waits up to `timeout` for another process to release
+/// the database before failing with [`AnalyticsError::Locked`].
+pub fn get_db_with_busy_timeout(path: &Path, timeout: StdDuration) -> Result<Connection> {
+    let mut conn = open_db(path, timeout)?;
+    migrate_db(&mut conn, path, timeout)?;
+    Ok(conn)
+}
+
+/// Opens the database at `path` without migrating an existing one. The
+/// migration adding `spotify_history_unique_play` deletes duplicate rows
+/// itself, so [`count_duplicates_sql`] would find nothing after it ran. A new
+/// database is still created at the latest schema.
+pub fn get_db_unmigrated(path: &Path, timeout: StdDuration) -> Result<Connection> {
+    let mut conn = open_db(path, timeout)?;
+    let version: u32 = conn.query_row("PRAGMA user_version", [], |r| r.get(0))?;
+    if version == 0 {
+        migrate_db(&mut conn, path, timeout)?;
+    }
+    Ok(conn)
+}
+
+fn open_db(path: &Path, timeout: StdDuration) -> Result<Connection> {
+    let conn = Connection::open(path)?;
+    conn.busy_timeout(timeout)?;
+    conn.pragma_update(None, "journal_mode", "WAL")
+        .map_err(|e| into_locked(e.into(), path, timeout))?;
+    Ok(conn)
+}
+
+fn migrate_db(conn: &mut Connection, path: &Path, timeout: StdDuration) -> Result<()> {
+    migrations()
+        .to_latest(conn)
+        .map_err(|e| into_locked(e.into(), path, timeout))
+}
+
+/// Turns an error from SQLite giving up on a locked database into
+/// [`AnalyticsError::Locked`], including one behind a failed batch.
+pub(crate) fn into_locked(
+    err: AnalyticsError,
+    path: &Path,
+    timeout: StdDuration,
+) -> AnalyticsError {
+    match err {
+        AnalyticsError::Db(ref e) | AnalyticsError::Insert { source: ref e, .. } if is_busy(e) => {
+            AnalyticsError::Locked {
+                path: path.to_path_buf(),
+                timeout,
+            }
+        }
+        AnalyticsError::Migration(rusqlite_migration::Error::RusqliteError { ref err, .. })
+            if is_busy(err) =>
+        {
+            AnalyticsError::Locked {
+                path: path.to_path_buf(),
+                timeout,
+            }
+        }
+        AnalyticsError::Save { committed, source } => AnalyticsError::Save {
+            committed,
+            source: Box::new(into_locked(*source, path, timeout)),
+        },
+        err => err,
+    }
+}
 
-    let mut conn = Connection::open("./spotify_history.db")?;
-    conn.pragma_update(None, "journal_mode", &"WAL")?;
+/// Maps errors from statements run on `conn` like [`into_locked`], reading
+/// the path and busy timeout back from the connection.
+fn locked_on(conn: &Connection) -> impl Fn(AnalyticsError) -> AnalyticsError {
+    let path = PathBuf::from(conn.path().unwrap_or_default());
+    let timeout = conn
+        .query_row("PRAGMA busy_timeout", [], |r| r.get(0))
+        .map_or(StdDuration::ZERO, StdDuration::from_millis);
+    move |err| into_locked(err, &path, timeout)
+}
 
-    migrations.to_latest(&mut conn)?;
+/// Rolls every migration back and re-applies them, leaving an empty database.
+/// Returns how many play rows were deleted.
+pub fn reset_db(path: &Path, busy_timeout: StdDuration) -> Result<u64> {
+    let mut conn = get_db_with_busy_timeout(path, busy_timeout)?;
+    let reset = |conn: &mut Connection| -> Result<u64> {
+        let deleted: u64 = conn.query_row(
+            "SELECT (SELECT COUNT(*) FROM spotify_history) + (SELECT COUNT(*) FROM video_history)",
+            [],
+            |r| r.get(0),
+        )?;
+        let migrations = migrations();
+        migrations.to_version(conn, 0)?;
+        migrations.to_latest(conn)?;
+        // Fold the WAL back into the main file and truncate it, so the
+        // dropped rows don't linger on disk.
+        conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))?;
+        conn.execute_batch("VACUUM")?;
+        Ok(deleted)
+    };
+    reset(&mut conn).map_err(|e| into_locked(e, path, busy_timeout))
+}
 
+/// Opens `path` read-only, without running migrations.
+pub fn get_db_read_only(path: &Path) -> Result<Connection> {
+    let conn = Connection::open_with_flags(
+        path,
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )?;
     Ok(conn)
 }
 
+/// Runs a read-only `sql` statement and returns its column names and rows
+/// rendered as text. NULLs become empty strings.
+pub fn query_sql(conn: &Connection, sql: &str) -> Result<(Vec<String>, Vec<Vec<String>>)> {
+    let mut stmt = conn.prepare(sql)?;
+    if !stmt.readonly() {
+        return Err(AnalyticsError::NotReadOnly);
+    }
+    let columns: Vec<String> = stmt.column_names().into_iter().map(String::from).collect();
+    let mut rows = Vec::new();
+    let mut query = stmt.query([])?;
+    while let Some(r) = query.next()? {
+        let row = (0..columns.len())
+            .map(|i| {
+                Ok(match r.get_ref(i)? {
+                    ValueRef::Null => String::new(),
+                    ValueRef::Integer(v) => v.to_string(),
+                    ValueRef::Real(v) => v.to_string(),
+                    ValueRef::Text(v) => String::from_utf8_lossy(v).into_owned(),
+                    ValueRef::Blob(v) => format!("<{} bytes>", v.len()),
+                })
+            })
+            .collect::<Result<_, rusqlite::Error>>()?;
+        rows.push(row);
+    }
+    Ok((columns, rows))
+}
+
+// Grouped on the same expressions as `spotify_history_unique_play`, so plays
+// of different users are never taken for duplicates.
+const DUPLICATE_ROWIDS: &str = "SELECT rowid FROM spotify_history WHERE rowid NOT IN (
+    SELECT MIN(rowid) FROM spotify_history
+    GROUP BY
+        ts,
+        IFNULL(spotify_track_uri, ''),
+        IFNULL(spotify_episode_uri, ''),
+        ms_played,
+        IFNULL(username, '')
+)";
+
+/// Counts rows that repeat an earlier row's `ts`, URI, `ms_played` and
+/// username.
+pub fn count_duplicates_sql(conn: &Connection) -> Result<u64> {
+    let n = conn.query_row(
+        &format!("SELECT COUNT(*) FROM ({DUPLICATE_ROWIDS})"),
+        [],
+        |r| r.get(0),
+    )?;
+    Ok(n)
+}
+
+/// Deletes the rows counted by [`count_duplicates_sql`], keeping the first
+/// copy of each, and returns how many were removed.
+pub fn delete_duplicates_sql(conn: &mut Connection) -> Result<usize> {
+    let locked = locked_on(conn);
+    let delete = |conn: &mut Connection| -> Result<usize> {
+        let tx = conn.transaction()?;
+        let n = tx.execute(
+            &format!("DELETE FROM spotify_history WHERE rowid IN ({DUPLICATE_ROWIDS})"),
+            [],
+        )?;
+        tx.commit()?;
+        Ok(n)
+    };
+    delete(conn).map_err(locked)
+}
+
+// Stored timestamps and bound parameters are formatted differently, so
+// both sides are compared through `julianday`. A NULL bound or username
+// matches every row.
+const RANGE_FILTER: &str = "(?1 IS NULL OR julianday(ts) >= julianday(?1))
+    AND (?2 IS NULL OR julianday(ts) <= julianday(?2))
+    AND (?3 IS NULL OR username = ?3)";
+
+/// Counts rows with `from <= ts <= to`, leaving a `None` bound open. With a
+/// `username`, only that user's rows are counted.
+pub fn count_range_sql(
+    conn: &Connection,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    username: Option<&str>,
+) -> Result<u64> {
+    let n = conn.query_row(
+        &format!("SELECT COUNT(*) FROM spotify_history WHERE {RANGE_FILTER}"),
+        (from, to, username),
+        |r| r.get(0),
+    )?;
+    Ok(n)
+}
+
+/// Deletes the rows counted by [`count_range_sql`] and returns how many were
+/// removed.
+pub fn delete_range_sql(
+    conn: &mut Connection,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    username: Option<&str>,
+) -> Result<usize> {
+    let locked = locked_on(conn);
+    let delete = |conn: &mut Connection| -> Result<usize> {
+        let tx = conn.transaction()?;
+        let n = tx.execute(
+            &format!("DELETE FROM spotify_history WHERE {RANGE_FILTER}"),
+            (from, to, username),
+        )?;
+        tx.commit()?;
+        Ok(n)
+    };
+    delete(conn).map_err(locked)
+}
+
+/// Labels the inclusive range `from..=to`, such as a trip or a life event.
+pub fn add_annotation_sql(
+    conn: &Connection,
+    label: &str,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> Result<()> {
+    conn.execute(
+        "INSERT INTO annotations (label, from_ts, to_ts) VALUES (?1, ?2, ?3)",
+        (label, from, to),
+    )
+    .map_err(|e| locked_on(conn)(e.into()))?;
+    Ok(())
+}
+
+pub fn top_artists_sql(conn: &Connection, n: usize) -> Result<Vec<(String, u64)>> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT artist, ms_played FROM v_top_artists
+        ORDER BY ms_played DESC, artist
+        LIMIT ?1",
+    )?;
+    let r = stmt
+        .query_map([n as i64], |r| Ok((r.get(0)?, r.get(1)?)))?
+        .collect::<Result<_, rusqlite::Error>>()?;
+    Ok(r)
+}
+
+pub fn top_tracks_sql(conn: &Connection, n: usize) -> Result<Vec<((String, String), u64)>> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT track, artist, ms_played FROM v_top_tracks
+        ORDER BY ms_played DESC, track, artist
+        LIMIT ?1",
+    )?;
+    let r = stmt
+        .query_map([n as i64], |r| Ok(((r.get(0)?, r.get(1)?), r.get(2)?)))?
+        .collect::<Result<_, rusqlite::Error>>()?;
+    Ok(r)
+}
+
+/// Like [`top_tracks_sql`], ranked by play count instead of ms_played.
+/// Podcast episodes are skipped.
+pub fn top_tracks_by_count_sql(
+    conn: &Connection,
+    n: usize,
+) -> Result<Vec<((String, String), u64)>> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT master_metadata_track_name AS track,
+            master_metadata_album_artist_name AS artist,
+            COUNT(*) AS plays
+        FROM spotify_history
+        WHERE master_metadata_track_name IS NOT NULL
+            AND master_metadata_album_artist_name IS NOT NULL
+            AND spotify_episode_uri IS NULL
+        GROUP BY track, artist
+        ORDER BY plays DESC, track, artist
+        LIMIT ?1",
+    )?;
+    let r = stmt
+        .query_map([n as i64], |r| Ok(((r.get(0)?, r.get(1)?), r.get(2)?)))?
+        .collect::<Result<_, rusqlite::Error>>()?;
+    Ok(r)
+}
+
+pub fn top_albums_sql(conn: &Connection, n: usize) -> Result<Vec<((String, String), u64)>> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT album, artist, ms_played FROM v_top_albums
+        ORDER BY ms_played DESC, album, artist
+        LIMIT ?1",
+    )?;
+    let r = stmt
+        .query_map([n as i64], |r| Ok(((r.get(0)?, r.get(1)?), r.get(2)?)))?
+        .collect::<Result<_, rusqlite::Error>>()?;
+    Ok(r)
+}
+
+pub fn top_shows_sql(conn: &Connection, n: usize) -> Result<Vec<(String, u64)>> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT show, ms_played FROM v_top_shows
+        ORDER BY ms_played DESC, show
+        LIMIT ?1",
+    )?;
+    let r = stmt
+        .query_map([n as i64], |r| Ok((r.get(0)?, r.get(1)?)))?
+        .collect::<Result<_, rusqlite::Error>>()?;
+    Ok(r)
+}
+
+/// Matches `.json` files and their gzipped `.json.gz` form.
+fn is_json_file_name(name: &str) -> bool {
+    name.ends_with(".json") || name.ends_with(".json.gz")
+}
+
+/// Matches gzipped tarballs, `.tar.gz` or `.tgz`.
+fn is_tar_gz_file_name(name: &str) -> bool {
+    name.ends_with(".tar.gz") || name.ends_with(".tgz")
+}
+
+/// Matches the `*Streaming_History*.json` entries of an extended Spotify data
+/// export and the `StreamingHistory*.json` entries of a basic one.
+fn is_streaming_history_file_name(name: &str) -> bool {
+    let file_name = name.rsplit('/').next().unwrap_or(name);
+    (file_name.contains("Streaming_History") || file_name.contains("StreamingHistory"))
+        && is_json_file_name(file_name)
+}
+
+/// Matches the `Streaming_History_Video_*.json` entries of an extended export,
+/// which are stored apart from audio plays.
+fn is_video_history_file_name(name: &str) -> bool {
+    let file_name = name.rsplit('/').next().unwrap_or(name);
+    file_name.contains("Streaming_History_Video") && is_json_file_name(file_name)
+}
+
+/// Maps a verbose export platform string such as
+/// `"Android OS 12 API 31 (Google, Pixel 6)"` to a coarse bucket.
+pub fn normalize_platform(platform: &str) -> &'static str {
+    let p = platform.to_lowercase();
+    if p.contains("web_player") || p.starts_with("web") {
+        "Web"
+    } else if p.contains("android") {
+        "Android"
+    } else if p.starts_with("ios") || p.contains("iphone") || p.contains("ipad") {
+        "iOS"
+    } else if p.contains("windows") {
+        "Windows"
+    } else if p.starts_with("os x") || p.contains("macos") {
+        "macOS"
+    } else if p.contains("linux") {
+        "Linux"
+    } else {
+        "Other"
+    }
+}
+
+/// Inserts `entries`, numbering them from `first_row` in errors, and returns
+/// how many were new.
+fn insert_entries(
+    tx: &Transaction,
+    entries: &[SpotifyHistoryEntry],
+    first_row: usize,
+    bar: &ProgressBar,
+) -> Result<usize> {
+    let mut stmt = tx.prepare_cached(
+        "INSERT OR IGNORE INTO spotify_history VALUES (
+        :ts,
+        :username,
+        :platform,
+        :ms_played,
+        :conn_country,
+        :ip_addr_decrypted,
+        :user_agent_decrypted,
+        :master_metadata_track_name,
+        :master_metadata_album_artist_name,
+        :master_metadata_album_album_name,
+        :spotify_track_uri,
+        :episode_name,
+        :episode_show_name,
+        :spotify_episode_uri,
+        :reason_start,
+        :reason_end,
+        :shuffle,
+        :skipped,
+        :offline,
+        :offline_timestamp,
+        :incognito_mode
+      );",
+    )?;
+    // Overlap with rows already in the table is left to the unique index
+    // rather than guessed from timestamps.
+    let mut inserted = 0;
+    for (i, e) in entries.iter().enumerate() {
+        let p = serde_rusqlite::to_params_named(e)?;
+        inserted +=
+            stmt.execute(p.to_slice().as_slice())
+                .map_err(|source| AnalyticsError::Insert {
+                    row: first_row + i,
+                    entry: format!("{:?}", e),
+                    source,
+                })?;
+        bar.inc(1);
+    }
+    Ok(inserted)
+}
+
+fn insert_videos(tx: &Transaction, entries: &[SpotifyVideoHistoryEntry]) -> Result<usize> {
+    let mut stmt = tx.prepare_cached(
+        "INSERT OR IGNORE INTO video_history VALUES (
+        :ts,
+        :username,
+        :platform,
+        :ms_played,
+        :conn_country,
+        :episode_name,
+        :episode_show_name,
+        :spotify_episode_uri,
+        :reason_start,
+        :reason_end,
+        :shuffle,
+        :skipped,
+        :offline,
+        :incognito_mode
+      );",
+    )?;
+    let mut inserted = 0;
+    for e in entries {
+        let p = serde_rusqlite::to_params_named(e)?;
+        inserted += stmt.execute(p.to_slice().as_slice())?;
+    }
+    Ok(inserted)
+}
+
+/// Returns the `(min, max)` play timestamps, or `(MAX_UTC, MIN_UTC)` when
+/// `history` is empty.
+fn ts_bounds(history: &[SpotifyHistoryEntry]) -> (DateTime<Utc>, DateTime<Utc>) {
+    let min_ts = history
+        .iter()
+        .map(|x| x.ts)
+        .min()
+        .unwrap_or(DateTime::<Utc>::MAX_UTC);
+    let max_ts = history
+        .iter()
+        .map(|x| x.ts)
+        .max()
+        .unwrap_or(DateTime::<Utc>::MIN_UTC);
+    (min_ts, max_ts)
+}
+
+/// Longest single play considered plausible.
+const MAX_PLAUSIBLE_MS_PLAYED: u64 = 24 * 60 * 60 * 1000;
+
+fn ms_played_anomaly(entry: &SpotifyHistoryEntry) -> Option<&'static str> {
+    if entry.ms_played > MAX_PLAUSIBLE_MS_PLAYED {
+        Some("play longer than 24 hours")
+    } else if entry.ms_played == 0 && entry.reason_end.as_deref() == Some("trackdone") {
+        Some("finished play with no time played")
+    } else {
+        None
+    }
+}
+
+/// Rows committed per transaction by [`SpotifyAnalytics::save`] unless told
+/// otherwise.
+pub const DEFAULT_BATCH_SIZE: usize = 10_000;
+
+/// Plays shorter than this count as zapped by [`SpotifyAnalytics::get_zapped_tracks`]
+/// unless told otherwise.
+pub const DEFAULT_ZAP_MAX_MS: u64 = 5_000;
+
+/// Per-stream rate used by [`SpotifyAnalytics::get_estimated_payout`] unless
+/// told otherwise.
+pub const DEFAULT_PAYOUT_PER_STREAM_USD: f64 = 0.003;
+
+/// Plays must last longer than this to count as a paid stream.
+const PAYOUT_MIN_MS: u64 = 30_000;
+
+/// Which kind of plays [`SpotifyAnalytics::scoped`] keeps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    Music,
+    Podcasts,
+}
+
 pub struct SpotifyAnalytics {
+    db_path: PathBuf,
     history: Vec<SpotifyHistoryEntry>,
+    incoming: Vec<SpotifyHistoryEntry>,
+    incoming_video: Vec<SpotifyVideoHistoryEntry>,
+    /// Malformed entries dropped while importing with `skip_invalid`.
+    invalid: usize,
+    /// Set once incoming entries were dropped by date, see
+    /// [`Self::drop_incoming_before`].
+    dropped_by_date: bool,
+    show_progress: bool,
+    skip_invalid: bool,
+    strict: bool,
+    force: bool,
+    username: Option<String>,
+    batch_size: usize,
+    busy_timeout: StdDuration,
+    track_durations: HashMap<String, u64>,
+    annotations: Vec<Annotation>,
+    started_at: DateTime<Utc>,
+    last_import: Option<DateTime<Utc>>,
     max_ts: DateTime<Utc>,
     min_ts: DateTime<Utc>,
 }
 
 impl SpotifyAnalytics {
-    pub fn new() -> Result<Self> {
-        let conn = get_db()?;
+    pub fn new<P>(db_path: P) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        Self::with_busy_timeout(db_path, StdDuration::from_secs(DEFAULT_BUSY_TIMEOUT_SECS))
+    }
+
+    /// Like [`Self::new`], but every connection it opens waits up to
+    /// `busy_timeout` for another process to release the database.
+    pub fn with_busy_timeout<P>(db_path: P, busy_timeout: StdDuration) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let db_path = db_path.as_ref().to_path_buf();
+        let conn = get_db_with_busy_timeout(&db_path, busy_timeout)?;
         let mut stmt = conn.prepare("SELECT * FROM spotify_history")?;
         let history: Vec<SpotifyHistoryEntry> =
             serde_rusqlite::from_rows::<SpotifyHistoryEntry>(stmt.query([])?)
                 .collect::<Result<_, serde_rusqlite::Error>>()?;
-        let max_ts = history
-            .iter()
-            .max_by_key(|x| x.ts)
-            .map(|x| x.ts)
-            .unwrap_or(DateTime::<Utc>::MIN_UTC);
-        let min_ts = history
-            .iter()
-            .min_by_key(|x| x.ts)
-            .map(|x| x.ts)
-            .unwrap_or(DateTime::<Utc>::MAX_UTC);
+        let last_import = conn
+            .query_row("SELECT last_import_ts FROM import_meta", [], |r| r.get(0))
+            .optional()?;
+        let mut stmt = conn.prepare("SELECT spotify_track_uri, duration_ms FROM track_duration")?;
+        let track_durations = stmt
+            .query_map([], |r| Ok((r.get(0)?, r.get(1)?)))?
+            .collect::<Result<_, rusqlite::Error>>()?;
+        let mut stmt = conn.prepare("SELECT label, from_ts, to_ts FROM annotations")?;
+        let annotations = stmt
+            .query_map([], |r| {
+                Ok(Annotation {
+                    label: r.get(0)?,
+                    from: r.get(1)?,
+                    to: r.get(2)?,
+                })
+            })?
+            .collect::<Result<_, rusqlite::Error>>()?;
         Ok(Self {
+            last_import,
+            track_durations,
+            annotations,
+            busy_timeout,
+            ..Self::from_history(db_path, history)
+        })
+    }
+
+    /// Opens the database without loading the history into memory. The
+    /// returned handle answers its queries in SQLite, which keeps startup
+    /// fast on large databases.
+    pub fn read_only<P>(db_path: P, busy_timeout: StdDuration) -> Result<ReadOnlyAnalytics>
+    where
+        P: AsRef<Path>,
+    {
+        Ok(ReadOnlyAnalytics {
+            conn: get_db_with_busy_timeout(db_path.as_ref(), busy_timeout)?,
+        })
+    }
+
+    fn from_history(db_path: PathBuf, history: Vec<SpotifyHistoryEntry>) -> Self {
+        let (min_ts, max_ts) = ts_bounds(&history);
+        Self {
+            db_path,
             history,
+            incoming: Vec::new(),
+            incoming_video: Vec::new(),
+            invalid: 0,
+            dropped_by_date: false,
+            show_progress: false,
+            skip_invalid: false,
+            strict: false,
+            force: false,
+            username: None,
+            batch_size: DEFAULT_BATCH_SIZE,
+            busy_timeout: StdDuration::from_secs(DEFAULT_BUSY_TIMEOUT_SECS),
+            track_durations: HashMap::new(),
+            annotations: Vec::new(),
+            started_at: Utc::now(),
+            last_import: None,
             max_ts,
             min_ts,
-        })
+        }
+    }
+
+    /// Shows progress bars on stderr while importing and saving.
+    pub fn set_show_progress(&mut self, show_progress: bool) {
+        self.show_progress = show_progress;
+    }
+
+    /// Drops malformed entries with a warning instead of failing the import.
+    pub fn set_skip_invalid(&mut self, skip_invalid: bool) {
+        self.skip_invalid = skip_invalid;
+    }
+
+    /// Fails the import on implausible `ms_played` values instead of only
+    /// warning about them.
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    /// Commits `save` every `batch_size` rows.
+    pub fn set_batch_size(&mut self, batch_size: usize) {
+        self.batch_size = batch_size;
+    }
+
+    /// Restricts the loaded history, and anything imported afterwards, to
+    /// plays by `username`.
+    pub fn set_username(&mut self, username: String) {
+        self.history
+            .retain(|x| x.username.as_deref() == Some(username.as_str()));
+        (self.min_ts, self.max_ts) = ts_bounds(&self.history);
+        self.username = Some(username);
+    }
+
+    /// Re-reads folder files even if they have not changed since the last
+    /// successful import.
+    pub fn set_force(&mut self, force: bool) {
+        self.force = force;
+    }
+
+    fn progress_bar(&self, len: usize, unit: &str) -> ProgressBar {
+        if !self.show_progress {
+            return ProgressBar::hidden();
+        }
+        ProgressBar::new(len as u64).with_style(
+            ProgressStyle::with_template(&format!(
+                "{{bar:40}} {{pos}}/{{len}} {unit} ({{elapsed}})"
+            ))
+            .unwrap_or_else(|_| ProgressStyle::default_bar()),
+        )
+    }
+
+    /// Returns a copy scoped to plays with `from <= ts <= to`.
+    pub fn filtered(&self, from: DateTime<Utc>, to: DateTime<Utc>) -> Self {
+        self.retained(|x| x.ts >= from && x.ts <= to)
+    }
+
+    /// Returns a copy scoped to podcast plays (those with an episode URI or
+    /// show name) or to everything else. Basic exports carry no URIs, so
+    /// their music plays only show up as not being episodes.
+    pub fn scoped(&self, scope: Scope) -> Self {
+        let is_episode = |x: &SpotifyHistoryEntry| {
+            x.spotify_episode_uri.is_some() || x.episode_show_name.is_some()
+        };
+        match scope {
+            Scope::Music => self.retained(|x| !is_episode(x)),
+            Scope::Podcasts => self.retained(is_episode),
+        }
+    }
+
+    fn retained(&self, keep: impl Fn(&SpotifyHistoryEntry) -> bool) -> Self {
+        let history = self.history.iter().filter(|x| keep(x)).cloned().collect();
+        Self {
+            track_durations: self.track_durations.clone(),
+            annotations: self.annotations.clone(),
+            ..Self::from_history(self.db_path.clone(), history)
+        }
     }
 
     #[instrument(skip(self), err)]
@@ -81,9 +855,19 @@ impl SpotifyAnalytics {
     where
         P: AsRef<Path> + Debug,
     {
-        let data = fs::read(path)?;
-        let history: Vec<SpotifyHistoryEntry> = serde_json::from_slice(&data)?;
-        self.history.extend(history);
+        let (history, invalid) = read_history_file(path.as_ref(), self.skip_invalid)?;
+        self.incoming.extend(history);
+        self.invalid += invalid;
+        Ok(())
+    }
+
+    /// Streams entries from `reader` straight into the pending imports, so the
+    /// raw JSON never has to be held in memory alongside the parsed entries.
+    pub fn deserialize_extended_streaming_history_json_reader<R>(&mut self, reader: R) -> Result<()>
+    where
+        R: Read,
+    {
+        self.invalid += read_history(reader, &mut self.incoming, self.skip_invalid)?;
         Ok(())
     }
 
@@ -95,106 +879,2701 @@ impl SpotifyAnalytics {
     where
         P: AsRef<Path> + Debug,
     {
-        for dir_entry in fs::read_dir(dir_path)? {
-            let dir_entry = dir_entry?;
-            let path = dir_entry.path();
+        let mut paths = Vec::new();
+        let mut dirs = vec![dir_path.as_ref().to_path_buf()];
+        while let Some(dir) = dirs.pop() {
+            for dir_entry in fs::read_dir(dir)? {
+                let dir_entry = dir_entry?;
+                let path = dir_entry.path();
+                let file_type = dir_entry.file_type()?;
 
-            if !dir_entry.file_type()?.is_file() {
-                info!(?path, "ignoring dir");
-                continue;
-            }
+                if file_type.is_dir() {
+                    dirs.push(path);
+                    continue;
+                }
+
+                if !file_type.is_file() {
+                    info!(?path, "ignoring non-regular file");
+                    continue;
+                }
 
-            if let Some(ext) = path.extension() {
-                if ext != "json" {
+                if !is_json_file_name(&path.to_string_lossy()) {
                     info!(?path, "ignoring non-json file");
                     continue;
                 }
+
+                if let (false, Some(last_import)) = (self.force, self.last_import) {
+                    let modified: DateTime<Utc> = dir_entry.metadata()?.modified()?.into();
+                    if modified < last_import {
+                        info!(?path, "ignoring file unchanged since last import");
+                        continue;
+                    }
+                }
+
+                if is_video_history_file_name(&path.to_string_lossy()) {
+                    self.incoming_video.extend(read_video_history_file(&path)?);
+                    continue;
+                }
+
+                paths.push(path);
             }
+        }
 
-            self.deserialize_extended_streaming_history_json(path)?
+        let bar = self.progress_bar(paths.len(), "files");
+        let skip_invalid = self.skip_invalid;
+        let histories = paths
+            .par_iter()
+            .map(|path| {
+                let history = read_history_file(path, skip_invalid);
+                bar.inc(1);
+                history
+            })
+            .collect::<Result<Vec<_>>>()?;
+        bar.finish_and_clear();
+        for (history, invalid) in histories {
+            self.incoming.extend(history);
+            self.invalid += invalid;
         }
         Ok(())
     }
 
-    pub fn save(&self) -> Result<()> {
-        let conn = get_db()?;
-
-        let mut stmt = conn.prepare_cached(
-            "INSERT INTO spotify_history VALUES (
-            :ts,
-            :username,
-            :platform,
-            :ms_played,
-            :conn_country,
-            :ip_addr_decrypted,
-            :user_agent_decrypted,
-            :master_metadata_track_name,
-            :master_metadata_album_artist_name,
-            :master_metadata_album_album_name,
-            :spotify_track_uri,
-            :episode_name,
-            :episode_show_name,
-            :spotify_episode_uri,
-            :reason_start,
-            :reason_end,
-            :shuffle,
-            :skipped,
-            :offline,
-            :offline_timestamp,
-            :incognito_mode
-          );",
-        )?;
-        for e in self
+    #[instrument(skip(self), err)]
+    pub fn deserialize_extended_streaming_history_zip<P>(&mut self, path: P) -> Result<()>
+    where
+        P: AsRef<Path> + Debug,
+    {
+        let mut archive = ZipArchive::new(BufReader::new(File::open(path)?))?;
+        for i in 0..archive.len() {
+            let file = archive.by_index(i)?;
+            let name = file.name()?.into_owned();
+
+            if !is_streaming_history_file_name(&name) {
+                info!(?name, "ignoring non-streaming-history archive entry");
+                continue;
+            }
+
+            let file = decompressed(&name, BufReader::new(file));
+            if is_video_history_file_name(&name) {
+                let videos = read_video_history(file).map_err(|source| AnalyticsError::Parse {
+                    name: format!("archive entry {name}"),
+                    source: Box::new(source),
+                })?;
+                self.incoming_video.extend(videos);
+                continue;
+            }
+
+            self.deserialize_extended_streaming_history_json_reader(file)
+                .map_err(|source| AnalyticsError::Parse {
+                    name: format!("archive entry {name}"),
+                    source: Box::new(source),
+                })?;
+        }
+        Ok(())
+    }
+
+    /// Like [`Self::deserialize_extended_streaming_history_zip`], for exports
+    /// delivered as a `.tar.gz` archive.
+    #[instrument(skip(self), err)]
+    pub fn deserialize_extended_streaming_history_tar_gz<P>(&mut self, path: P) -> Result<()>
+    where
+        P: AsRef<Path> + Debug,
+    {
+        let mut archive = Archive::new(GzDecoder::new(BufReader::new(File::open(path)?)));
+        for file in archive.entries()? {
+            let file = file?;
+            let name = file.path()?.to_string_lossy().into_owned();
+
+            if !is_streaming_history_file_name(&name) {
+                info!(?name, "ignoring non-streaming-history archive entry");
+                continue;
+            }
+
+            let file = decompressed(&name, BufReader::new(file));
+            if is_video_history_file_name(&name) {
+                let videos = read_video_history(file).map_err(|source| AnalyticsError::Parse {
+                    name: format!("archive entry {name}"),
+                    source: Box::new(source),
+                })?;
+                self.incoming_video.extend(videos);
+                continue;
+            }
+
+            self.deserialize_extended_streaming_history_json_reader(file)
+                .map_err(|source| AnalyticsError::Parse {
+                    name: format!("archive entry {name}"),
+                    source: Box::new(source),
+                })?;
+        }
+        Ok(())
+    }
+
+    /// Imports `path` as a single history file or as a folder of them.
+    #[instrument(skip(self), err)]
+    pub fn import_path<P>(&mut self, path: P) -> Result<()>
+    where
+        P: AsRef<Path> + Debug,
+    {
+        let start = self.incoming.len();
+        if fs::metadata(&path)?.is_dir() {
+            self.deserialize_extended_streaming_history_json_files_from_folder(path)?;
+        } else if path.as_ref().extension().is_some_and(|ext| ext == "zip") {
+            self.deserialize_extended_streaming_history_zip(path)?;
+        } else if is_tar_gz_file_name(&path.as_ref().to_string_lossy()) {
+            self.deserialize_extended_streaming_history_tar_gz(path)?;
+        } else if is_video_history_file_name(&path.as_ref().to_string_lossy()) {
+            let videos = read_video_history_file(path.as_ref())?;
+            self.incoming_video.extend(videos);
+        } else {
+            self.deserialize_extended_streaming_history_json(path)?;
+        }
+        self.check_incoming(start)
+    }
+
+    /// Imports a JSON array or JSON Lines history from `reader`, applying the
+    /// same username filter and validation as [`Self::import_path`].
+    pub fn import_reader<R: Read>(&mut self, reader: R) -> Result<()> {
+        let start = self.incoming.len();
+        self.deserialize_extended_streaming_history_json_reader(reader)?;
+        self.check_incoming(start)
+    }
+
+    /// Applies the username filter to plays and video plays, then warns about incoming entries from
+    /// `start` on whose `ms_played` looks corrupt, or fails on the first one
+    /// when strict.
+    fn check_incoming(&mut self, start: usize) -> Result<()> {
+        if let Some(username) = &self.username {
+            self.incoming
+                .retain(|x| x.username.as_deref() == Some(username.as_str()));
+            self.incoming_video
+                .retain(|x| x.username.as_deref() == Some(username.as_str()));
+        }
+        for x in &self.incoming[start..] {
+            let Some(anomaly) = ms_played_anomaly(x) else {
+                continue;
+            };
+            if self.strict {
+                return Err(AnalyticsError::ImplausiblePlay {
+                    anomaly,
+                    ts: x.ts,
+                    ms_played: x.ms_played,
+                });
+            }
+            warn!(ts = %x.ts, ms_played = x.ms_played, "{anomaly}");
+        }
+        Ok(())
+    }
+
+    /// Drops incoming entries that repeat an earlier one on the columns of
+    /// the table's unique index, returning how many were removed.
+    pub fn dedup_incoming(&mut self) -> usize {
+        let before = self.incoming.len();
+        let mut seen = HashSet::new();
+        self.incoming.retain(|x| {
+            seen.insert((
+                x.ts,
+                x.spotify_track_uri.clone(),
+                x.spotify_episode_uri.clone(),
+                x.ms_played,
+                x.username.clone(),
+            ))
+        });
+        before - self.incoming.len()
+    }
+
+    /// Drops incoming plays and video plays from before `since`, returning
+    /// how many were removed.
+    pub fn drop_incoming_before(&mut self, since: DateTime<Utc>) -> usize {
+        self.dropped_by_date = true;
+        let before = self.incoming.len() + self.incoming_video.len();
+        self.incoming.retain(|x| x.ts >= since);
+        self.incoming_video.retain(|x| x.ts >= since);
+        before - self.incoming.len() - self.incoming_video.len()
+    }
+
+    /// Inserts the incoming entries, committing every `batch_size` rows so a
+    /// huge import never holds one giant transaction open. Re-running after a
+    /// failure is safe since already committed rows are ignored.
+    pub fn save(&self) -> Result<ImportReport> {
+        let mut conn = get_db_with_busy_timeout(&self.db_path, self.busy_timeout)?;
+        let bar = self.progress_bar(self.incoming.len(), "rows");
+        let batch_size = self.batch_size.max(1);
+        let batch_count = self.incoming.len().div_ceil(batch_size).max(1);
+        let mut inserted = 0;
+        for i in 0..batch_count {
+            let first_row = i * batch_size;
+            let batch = &self.incoming[first_row..self.incoming.len().min(first_row + batch_size)];
+            inserted += self
+                .save_batch(&mut conn, batch, first_row, i + 1 == batch_count, &bar)
+                .map_err(|source| AnalyticsError::Save {
+                    committed: first_row,
+                    source: Box::new(into_locked(source, &self.db_path, self.busy_timeout)),
+                })?;
+        }
+        bar.finish_and_clear();
+        Ok(ImportReport {
+            inserted,
+            skipped_duplicates: self.incoming.len() - inserted,
+            errors: self.invalid,
+        })
+    }
+
+    fn save_batch(
+        &self,
+        conn: &mut Connection,
+        batch: &[SpotifyHistoryEntry],
+        first_row: usize,
+        last: bool,
+        bar: &ProgressBar,
+    ) -> Result<usize> {
+        // Dropping the transaction without committing rolls back, so a bad
+        // row leaves its batch untouched.
+        let tx = conn.transaction()?;
+        let inserted = insert_entries(&tx, batch, first_row, bar)?;
+        if last {
+            insert_videos(&tx, &self.incoming_video)?;
+            // Only a complete, unfiltered import moves the incremental
+            // cutoff: files modified while it was running are picked up next
+            // time, and so are plays a username or date filter left out.
+            if self.username.is_none() && !self.dropped_by_date {
+                tx.execute(
+                    "INSERT OR REPLACE INTO import_meta (id, last_import_ts) VALUES (1, ?1)",
+                    [self.started_at],
+                )?;
+            }
+        }
+        tx.commit()?;
+        Ok(inserted)
+    }
+
+    /// Counts how many incoming entries `save` would insert, by running the
+    /// inserts in a transaction that is then rolled back.
+    pub fn count_new_entries(&self) -> Result<usize> {
+        let mut conn = get_db_with_busy_timeout(&self.db_path, self.busy_timeout)?;
+        let count = |conn: &mut Connection| -> Result<usize> {
+            let tx = conn.transaction()?;
+            let bar = self.progress_bar(self.incoming.len(), "rows");
+            let inserted = insert_entries(&tx, &self.incoming, 0, &bar)?;
+            bar.finish_and_clear();
+            tx.rollback()?;
+            Ok(inserted)
+        };
+        count(&mut conn).map_err(|e| into_locked(e, &self.db_path, self.busy_timeout))
+    }
+
+    pub fn history(&self) -> &[SpotifyHistoryEntry] {
+        &self.history
+    }
+
+    /// Entries imported since construction that `save` will insert.
+    pub fn incoming(&self) -> &[SpotifyHistoryEntry] {
+        &self.incoming
+    }
+
+    /// Video plays imported since construction that `save` will insert into
+    /// the `video_history` table.
+    pub fn incoming_video(&self) -> &[SpotifyVideoHistoryEntry] {
+        &self.incoming_video
+    }
+
+    pub fn incoming_date_range(&self) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+        let min_ts = self.incoming.iter().map(|x| x.ts).min()?;
+        let max_ts = self.incoming.iter().map(|x| x.ts).max()?;
+        Some((min_ts, max_ts))
+    }
+
+    pub fn total_plays(&self) -> usize {
+        self.history.len()
+    }
+
+    pub fn total_ms_played(&self) -> u64 {
+        self.history
+            .iter()
+            .fold(0_u64, |acc, x| acc.saturating_add(x.ms_played))
+    }
+
+    /// Returns the `(earliest, latest)` play timestamps, or `None` when the
+    /// history is empty.
+    pub fn date_range(&self) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+        if self.min_ts > self.max_ts {
+            return None;
+        }
+        Some((self.min_ts, self.max_ts))
+    }
+
+    /// Plays whose artist matches `artist`, ignoring case.
+    fn plays_by_artist<'a>(
+        &'a self,
+        artist: &str,
+    ) -> impl Iterator<Item = &'a SpotifyHistoryEntry> + 'a {
+        let artist = artist.to_lowercase();
+        self.history.iter().filter(move |x| {
+            x.master_metadata_album_artist_name
+                .as_ref()
+                .is_some_and(|a| a.to_lowercase() == artist)
+        })
+    }
+
+    /// Artist names containing `query`, ignoring case, most listened first.
+    pub fn search_artists(&self, query: &str) -> Vec<&str> {
+        let query = query.to_lowercase();
+        self.get_all_top_artists()
+            .into_iter()
+            .filter(|(a, _)| a.to_lowercase().contains(&query))
+            .map(|(a, _)| a)
+            .collect()
+    }
+
+    pub fn get_play_range(&self, artist: &str) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+        self.plays_by_artist(artist).fold(None, |r, x| match r {
+            None => Some((x.ts, x.ts)),
+            Some((min, max)) => Some((min.min(x.ts), max.max(x.ts))),
+        })
+    }
+
+    /// Every timestamp `uri` was played at, matched exactly against
+    /// `spotify_track_uri`, in chronological order.
+    pub fn get_play_events_for_uri(&self, uri: &str) -> Vec<DateTime<Utc>> {
+        let mut r: Vec<DateTime<Utc>> = self
             .history
             .iter()
-            .filter(move |x| x.ts > self.max_ts && x.ts < self.min_ts)
-        {
-            let p = serde_rusqlite::to_params_named(e)?;
-            stmt.execute(p.to_slice().as_slice())
-                .with_context(|| format!("{:?}", e))?;
+            .filter(|x| x.spotify_track_uri.as_deref() == Some(uri))
+            .map(|x| x.ts)
+            .collect();
+        r.sort_unstable();
+        r
+    }
+
+    pub fn get_artist_summary(&self, artist: &str) -> Option<ArtistSummary> {
+        let mut plays = self.plays_by_artist(artist).peekable();
+        let name = plays.peek()?.master_metadata_album_artist_name.clone()?;
+        let mut tracks = HashSet::new();
+        let mut summary = ArtistSummary {
+            name,
+            play_count: 0,
+            ms_played: 0,
+            distinct_tracks: 0,
+            first_play: DateTime::<Utc>::MAX_UTC,
+            last_play: DateTime::<Utc>::MIN_UTC,
+        };
+        for x in plays {
+            summary.play_count += 1;
+            summary.ms_played = summary.ms_played.saturating_add(x.ms_played);
+            summary.first_play = summary.first_play.min(x.ts);
+            summary.last_play = summary.last_play.max(x.ts);
+            if let Some(t) = x.master_metadata_track_name.as_ref() {
+                tracks.insert(t.as_str());
+            }
         }
-        Ok(())
+        summary.distinct_tracks = tracks.len();
+        Some(summary)
     }
 
-    pub fn get_all_top_artists(&self) -> Vec<(&str, u64)> {
+    /// The `n` tracks by `artist`, matched case-insensitively, with the most
+    /// ms_played.
+    pub fn get_top_tracks_for_artist(&self, artist: &str, n: usize) -> Vec<(&str, u64)> {
         let mut s = HashMap::new();
-        for x in self.history.iter() {
-            if let Some(a) = x.master_metadata_album_artist_name.as_ref() {
-                let p = s.entry(a.as_str()).or_insert(0_u64);
+        for x in self.plays_by_artist(artist) {
+            if let Some(t) = x.master_metadata_track_name.as_ref() {
+                let p = s.entry(t.as_str()).or_insert(0_u64);
                 *p = p.saturating_add(x.ms_played);
             }
         }
         let mut r: Vec<(&str, u64)> = s.into_iter().collect();
-        r.sort_by(|a, b| b.1.cmp(&a.1));
+        r.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+        r.truncate(n);
         r
     }
 
-    pub fn get_top_10_artists(&self) -> Vec<(&str, u64)> {
-        self.get_all_top_artists().into_iter().take(10).collect()
+    /// Total ms_played per `(year, month)` for `artist`, matched
+    /// case-insensitively, in chronological order.
+    pub fn get_artist_monthly_trend(&self, artist: &str) -> Vec<((i32, u32), u64)> {
+        let mut s = BTreeMap::new();
+        for x in self.plays_by_artist(artist) {
+            let p = s.entry((x.ts.year(), x.ts.month())).or_insert(0_u64);
+            *p = p.saturating_add(x.ms_played);
+        }
+        s.into_iter().collect()
     }
-}
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct SpotifyHistoryEntry {
-    pub ts: DateTime<Utc>,
-    pub username: Option<String>,
-    pub platform: Option<String>,
-    pub ms_played: u64,
-    pub conn_country: Option<String>,
-    pub ip_addr_decrypted: Option<String>,
-    pub user_agent_decrypted: Option<String>,
-    pub master_metadata_track_name: Option<String>,
-    pub master_metadata_album_artist_name: Option<String>,
-    pub master_metadata_album_album_name: Option<String>,
-    pub spotify_track_uri: Option<String>,
-    pub episode_name: Option<String>,
-    pub episode_show_name: Option<String>,
-    pub spotify_episode_uri: Option<String>,
-    pub reason_start: Option<String>,
-    pub reason_end: Option<String>,
-    pub shuffle: Option<bool>,
-    pub skipped: Option<bool>,
-    pub offline: Option<bool>,
-    pub offline_timestamp: Option<u64>,
-    pub incognito_mode: Option<bool>,
+    /// Returns `(completed, skipped, other)` play counts based on `reason_end`.
+    pub fn get_completion_stats(&self) -> (u64, u64, u64) {
+        let mut r = (0_u64, 0_u64, 0_u64);
+        for x in self.history.iter() {
+            match x.reason_end.as_deref() {
+                Some("trackdone") => r.0 += 1,
+                Some("fwdbtn" | "backbtn") => r.1 += 1,
+                _ => r.2 += 1,
+            }
+        }
+        r
+    }
+
+    /// Approximates how far plays got by grouping `reason_end` into
+    /// `finished`, `skipped`, `interrupted` (playback stopped some other way)
+    /// and `unknown`, returned in that order with their play counts.
+    pub fn get_completion_buckets(&self) -> Vec<(&'static str, u64)> {
+        let mut r = [
+            ("finished", 0_u64),
+            ("skipped", 0),
+            ("interrupted", 0),
+            ("unknown", 0),
+        ];
+        for x in self.history.iter() {
+            let i = match x.reason_end.as_deref() {
+                Some("trackdone") => 0,
+                Some("fwdbtn" | "backbtn") => 1,
+                Some(_) => 2,
+                None => 3,
+            };
+            r[i].1 += 1;
+        }
+        r.to_vec()
+    }
+
+    pub fn count_unique_artists(&self) -> usize {
+        self.history
+            .iter()
+            .filter_map(|x| x.master_metadata_album_artist_name.as_deref())
+            .collect::<HashSet<_>>()
+            .len()
+    }
+
+    /// Counts tracks by `spotify_track_uri`, falling back to
+    /// `(track name, artist name)` for entries without a URI.
+    pub fn count_unique_tracks(&self) -> usize {
+        self.history
+            .iter()
+            .filter_map(|x| {
+                if let Some(uri) = x.spotify_track_uri.as_deref() {
+                    return Some((uri, None));
+                }
+                Some((
+                    x.master_metadata_track_name.as_deref()?,
+                    Some(x.master_metadata_album_artist_name.as_deref()?),
+                ))
+            })
+            .collect::<HashSet<_>>()
+            .len()
+    }
+
+    /// Counts albums by `(album name, artist name)`. Exports carry no album
+    /// URI, so the artist is what tells same-named albums apart.
+    pub fn count_unique_albums(&self) -> usize {
+        self.history
+            .iter()
+            .filter_map(|x| {
+                Some((
+                    x.master_metadata_album_album_name.as_deref()?,
+                    x.master_metadata_album_artist_name.as_deref()?,
+                ))
+            })
+            .collect::<HashSet<_>>()
+            .len()
+    }
+
+    pub fn count_unique_shows(&self) -> usize {
+        self.history
+            .iter()
+            .filter_map(|x| x.episode_show_name.as_deref())
+            .collect::<HashSet<_>>()
+            .len()
+    }
+
+    /// Distinct usernames present in the history, sorted.
+    pub fn get_usernames(&self) -> Vec<&str> {
+        let mut r: Vec<&str> = self
+            .history
+            .iter()
+            .filter_map(|x| x.username.as_deref())
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect();
+        r.sort_unstable();
+        r
+    }
+
+    /// First play of each artist, in chronological order.
+    pub fn get_artist_discovery_timeline(&self) -> Vec<(&str, DateTime<Utc>)> {
+        let mut s = HashMap::new();
+        for x in self.history.iter() {
+            if let Some(a) = x.master_metadata_album_artist_name.as_ref() {
+                let p = s.entry(a.as_str()).or_insert(x.ts);
+                *p = (*p).min(x.ts);
+            }
+        }
+        let mut r: Vec<(&str, DateTime<Utc>)> = s.into_iter().collect();
+        r.sort_by(|a, b| a.1.cmp(&b.1).then_with(|| a.0.cmp(b.0)));
+        r
+    }
+
+    /// Artists with exactly one play in the history, ordered by when that
+    /// play happened.
+    pub fn get_one_play_artists(&self) -> Vec<&str> {
+        let mut s = HashMap::new();
+        for x in self.history.iter() {
+            if let Some(a) = x.master_metadata_album_artist_name.as_ref() {
+                let p = s.entry(a.as_str()).or_insert((0_u64, x.ts));
+                p.0 += 1;
+                p.1 = p.1.min(x.ts);
+            }
+        }
+        let mut r: Vec<(&str, DateTime<Utc>)> = s
+            .into_iter()
+            .filter(|(_, (plays, _))| *plays == 1)
+            .map(|(a, (_, ts))| (a, ts))
+            .collect();
+        r.sort_by(|a, b| a.1.cmp(&b.1).then_with(|| a.0.cmp(b.0)));
+        r.into_iter().map(|(a, _)| a).collect()
+    }
+
+    /// The `n` most listened artists with no plays in the last `recent_days`
+    /// before the latest play in the history. A window reaching past the
+    /// representable dates covers every play, so no artist has faded.
+    pub fn get_faded_artists(&self, recent_days: i64, n: usize) -> Vec<&str> {
+        let Some((_, max_ts)) = self.date_range() else {
+            return Vec::new();
+        };
+        let Some(cutoff) =
+            Duration::try_days(recent_days).and_then(|d| max_ts.checked_sub_signed(d))
+        else {
+            return Vec::new();
+        };
+        let mut s = HashMap::new();
+        for x in self.history.iter() {
+            if let Some(a) = x.master_metadata_album_artist_name.as_ref() {
+                let p = s.entry(a.as_str()).or_insert((0_u64, x.ts));
+                p.0 = p.0.saturating_add(x.ms_played);
+                p.1 = p.1.max(x.ts);
+            }
+        }
+        let mut r: Vec<(&str, u64)> = s
+            .into_iter()
+            .filter(|(_, (_, last_play))| *last_play <= cutoff)
+            .map(|(a, (ms, _))| (a, ms))
+            .collect();
+        r.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+        r.into_iter().take(n).map(|(a, _)| a).collect()
+    }
+
+    pub fn get_all_top_artists(&self) -> Vec<(&str, u64)> {
+        let mut s = HashMap::new();
+        for x in self.history.iter() {
+            if let Some(a) = x.master_metadata_album_artist_name.as_ref() {
+                let p = s.entry(a.as_str()).or_insert(0_u64);
+                *p = p.saturating_add(x.ms_played);
+            }
+        }
+        let mut r: Vec<(&str, u64)> = s.into_iter().collect();
+        r.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+        r
+    }
+
+    /// Like [`Self::get_all_top_artists`], but merges names that only differ
+    /// in casing or surrounding whitespace. Each group is shown under its
+    /// most-played spelling, trimmed.
+    pub fn get_all_top_artists_normalized(&self) -> Vec<(&str, u64)> {
+        let variants = self.get_all_top_artists();
+        let mut s: HashMap<String, (&str, u64)> = HashMap::new();
+        for (name, ms) in variants {
+            let name = name.trim();
+            let p = s.entry(name.to_lowercase()).or_insert((name, 0));
+            p.1 = p.1.saturating_add(ms);
+        }
+        let mut r: Vec<(&str, u64)> = s.into_values().collect();
+        r.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+        r
+    }
+
+    /// How evenly listening time is spread across artists, from 0.0 (all of
+    /// it on one artist) to 1.0 (every artist played equally long).
+    ///
+    /// This is the normalized Shannon entropy of the per-artist shares: with
+    /// `p_i = ms_i / total` over the `n` artists with any listening time,
+    /// `H = -sum(p_i * ln(p_i))` and the result is `H / ln(n)`. Fewer than
+    /// two such artists give 0.0.
+    pub fn get_listening_diversity(&self) -> f64 {
+        let ms: Vec<u64> = self
+            .get_all_top_artists()
+            .into_iter()
+            .map(|(_, ms)| ms)
+            .filter(|ms| *ms > 0)
+            .collect();
+        if ms.len() < 2 {
+            return 0.0;
+        }
+        let total = ms.iter().fold(0_u64, |acc, ms| acc.saturating_add(*ms)) as f64;
+        let entropy: f64 = ms
+            .iter()
+            .map(|ms| {
+                let p = *ms as f64 / total;
+                -p * p.ln()
+            })
+            .sum();
+        (entropy / (ms.len() as f64).ln()).clamp(0.0, 1.0)
+    }
+
+    pub fn get_top_n_artists(&self, n: usize) -> Vec<(&str, u64)> {
+        self.get_all_top_artists().into_iter().take(n).collect()
+    }
+
+    pub fn get_top_10_artists(&self) -> Vec<(&str, u64)> {
+        self.get_top_n_artists(10)
+    }
+
+    pub fn get_all_top_tracks(&self) -> Vec<((&str, &str), u64)> {
+        let mut s = HashMap::new();
+        for x in self.history.iter() {
+            if let (Some(t), Some(a)) = (
+                x.master_metadata_track_name.as_ref(),
+                x.master_metadata_album_artist_name.as_ref(),
+            ) {
+                let p = s.entry((t.as_str(), a.as_str())).or_insert(0_u64);
+                *p = p.saturating_add(x.ms_played);
+            }
+        }
+        let mut r: Vec<((&str, &str), u64)> = s.into_iter().collect();
+        r.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        r
+    }
+
+    pub fn get_top_n_tracks(&self, n: usize) -> Vec<((&str, &str), u64)> {
+        self.get_all_top_tracks().into_iter().take(n).collect()
+    }
+
+    /// Counts plays flagged `skipped` per `(track, artist)`.
+    pub fn get_most_skipped_tracks(&self, n: usize) -> Vec<((&str, &str), u64)> {
+        let mut s = HashMap::new();
+        for x in self.history.iter().filter(|x| x.skipped == Some(true)) {
+            if let (Some(t), Some(a)) = (
+                x.master_metadata_track_name.as_ref(),
+                x.master_metadata_album_artist_name.as_ref(),
+            ) {
+                *s.entry((t.as_str(), a.as_str())).or_insert(0_u64) += 1;
+            }
+        }
+        let mut r: Vec<((&str, &str), u64)> = s.into_iter().collect();
+        r.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        r.truncate(n);
+        r
+    }
+
+    /// Counts play events per `(track, artist)`, regardless of how long each
+    /// play lasted. Podcast episodes are skipped.
+    pub fn get_most_repeated_tracks(&self, n: usize) -> Vec<((&str, &str), u64)> {
+        let mut s = HashMap::new();
+        for x in self
+            .history
+            .iter()
+            .filter(|x| x.spotify_episode_uri.is_none())
+        {
+            if let (Some(t), Some(a)) = (
+                x.master_metadata_track_name.as_ref(),
+                x.master_metadata_album_artist_name.as_ref(),
+            ) {
+                *s.entry((t.as_str(), a.as_str())).or_insert(0_u64) += 1;
+            }
+        }
+        let mut r: Vec<((&str, &str), u64)> = s.into_iter().collect();
+        r.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        r.truncate(n);
+        r
+    }
+
+    /// Counts plays per `(track, artist)` that lasted under `max_ms`, to find
+    /// tracks that get abandoned straight away whether or not Spotify flagged
+    /// them as `skipped`.
+    pub fn get_zapped_tracks(&self, max_ms: u64, n: usize) -> Vec<((&str, &str), u64)> {
+        let mut s = HashMap::new();
+        for x in self.history.iter().filter(|x| x.ms_played < max_ms) {
+            if let (Some(t), Some(a)) = (
+                x.master_metadata_track_name.as_ref(),
+                x.master_metadata_album_artist_name.as_ref(),
+            ) {
+                *s.entry((t.as_str(), a.as_str())).or_insert(0_u64) += 1;
+            }
+        }
+        let mut r: Vec<((&str, &str), u64)> = s.into_iter().collect();
+        r.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        r.truncate(n);
+        r
+    }
+
+    pub fn get_all_top_albums(&self) -> Vec<((&str, &str), u64)> {
+        let mut s = HashMap::new();
+        for x in self.history.iter() {
+            if let (Some(al), Some(a)) = (
+                x.master_metadata_album_album_name.as_ref(),
+                x.master_metadata_album_artist_name.as_ref(),
+            ) {
+                let p = s.entry((al.as_str(), a.as_str())).or_insert(0_u64);
+                *p = p.saturating_add(x.ms_played);
+            }
+        }
+        let mut r: Vec<((&str, &str), u64)> = s.into_iter().collect();
+        r.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        r
+    }
+
+    pub fn get_top_n_albums(&self, n: usize) -> Vec<((&str, &str), u64)> {
+        self.get_all_top_albums().into_iter().take(n).collect()
+    }
+
+    /// Returns `(artist, skip rate, play count)` for artists with at least
+    /// `min_plays` plays, sorted by skip rate descending.
+    pub fn get_skip_rate_by_artist(&self, min_plays: u64) -> Vec<(&str, f64, u64)> {
+        let mut s = HashMap::new();
+        for x in self.history.iter() {
+            if let Some(a) = x.master_metadata_album_artist_name.as_ref() {
+                let (skipped, plays) = s.entry(a.as_str()).or_insert((0_u64, 0_u64));
+                if x.skipped == Some(true) {
+                    *skipped += 1;
+                }
+                *plays += 1;
+            }
+        }
+        let mut r: Vec<(&str, f64, u64)> = s
+            .into_iter()
+            .filter(|(_, (_, plays))| *plays >= min_plays.max(1))
+            .map(|(a, (skipped, plays))| (a, skipped as f64 / plays as f64, plays))
+            .collect();
+        r.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+        r
+    }
+
+    /// Estimates what each artist was paid for the listening, counting plays
+    /// over 30 seconds at `per_stream_usd` each. Sorted by payout descending.
+    pub fn get_estimated_payout(&self, per_stream_usd: f64) -> Vec<(&str, f64)> {
+        let mut s = HashMap::new();
+        for x in self.history.iter() {
+            if x.ms_played <= PAYOUT_MIN_MS {
+                continue;
+            }
+            if let Some(a) = x.master_metadata_album_artist_name.as_ref() {
+                let v = s.entry(a.as_str()).or_insert(0_u64);
+                *v = v.saturating_add(1);
+            }
+        }
+        let mut r: Vec<(&str, u64)> = s.into_iter().collect();
+        r.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+        r.into_iter()
+            .map(|(a, streams)| (a, streams as f64 * per_stream_usd))
+            .collect()
+    }
+
+    pub fn get_listening_by_platform(&self) -> Vec<(&str, u64)> {
+        let mut s = HashMap::new();
+        for x in self.history.iter() {
+            if let Some(pl) = x.platform.as_ref() {
+                let p = s.entry(pl.as_str()).or_insert(0_u64);
+                *p = p.saturating_add(x.ms_played);
+            }
+        }
+        let mut r: Vec<(&str, u64)> = s.into_iter().collect();
+        r.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+        r
+    }
+
+    /// First play on each platform, in chronological order.
+    pub fn get_platform_first_seen(&self) -> Vec<(&str, DateTime<Utc>)> {
+        let mut s = HashMap::new();
+        for x in self.history.iter() {
+            if let Some(pl) = x.platform.as_ref() {
+                let p = s.entry(pl.as_str()).or_insert(x.ts);
+                *p = (*p).min(x.ts);
+            }
+        }
+        let mut r: Vec<(&str, DateTime<Utc>)> = s.into_iter().collect();
+        r.sort_by(|a, b| a.1.cmp(&b.1).then_with(|| a.0.cmp(b.0)));
+        r
+    }
+
+    /// Like [`Self::get_listening_by_platform`], grouped by [`normalize_platform`].
+    pub fn get_listening_by_platform_normalized(&self) -> Vec<(&'static str, u64)> {
+        let mut s = HashMap::new();
+        for (pl, ms) in self.get_listening_by_platform() {
+            let p = s.entry(normalize_platform(pl)).or_insert(0_u64);
+            *p = p.saturating_add(ms);
+        }
+        let mut r: Vec<(&str, u64)> = s.into_iter().collect();
+        r.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+        r
+    }
+
+    pub fn get_listening_by_country(&self) -> Vec<(&str, u64)> {
+        let mut s = HashMap::new();
+        for x in self.history.iter() {
+            if let Some(c) = x.conn_country.as_ref() {
+                let p = s.entry(c.as_str()).or_insert(0_u64);
+                *p = p.saturating_add(x.ms_played);
+            }
+        }
+        let mut r: Vec<(&str, u64)> = s.into_iter().collect();
+        r.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+        r
+    }
+
+    /// Total ms_played inside the ranges of each annotation label, most
+    /// listened first. A play covered by several annotations counts towards
+    /// each of them.
+    pub fn get_listening_by_annotation(&self) -> Vec<(String, u64)> {
+        let mut s = HashMap::new();
+        for an in self.annotations.iter() {
+            let ms = self
+                .history
+                .iter()
+                .filter(|x| x.ts >= an.from && x.ts <= an.to)
+                .fold(0_u64, |acc, x| acc.saturating_add(x.ms_played));
+            let p = s.entry(an.label.as_str()).or_insert(0_u64);
+            *p = p.saturating_add(ms);
+        }
+        let mut r: Vec<(String, u64)> = s.into_iter().map(|(l, ms)| (l.to_owned(), ms)).collect();
+        r.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        r
+    }
+
+    pub fn get_listening_by_year(&self) -> Vec<(i32, u64)> {
+        let mut s = BTreeMap::new();
+        for x in self.history.iter() {
+            let p = s.entry(x.ts.year()).or_insert(0_u64);
+            *p = p.saturating_add(x.ms_played);
+        }
+        s.into_iter().collect()
+    }
+
+    /// Total ms_played per calendar month across all years, indexed from
+    /// January (`0`) to December (`11`).
+    pub fn get_listening_by_month(&self) -> [u64; 12] {
+        let mut r = [0_u64; 12];
+        for x in self.history.iter() {
+            let p = &mut r[x.ts.month0() as usize];
+            *p = p.saturating_add(x.ms_played);
+        }
+        r
+    }
+
+    /// Total ms_played per `(year, month)` in chronological order.
+    pub fn get_listening_by_year_month(&self) -> Vec<((i32, u32), u64)> {
+        let mut s = BTreeMap::new();
+        for x in self.history.iter() {
+            let p = s.entry((x.ts.year(), x.ts.month())).or_insert(0_u64);
+            *p = p.saturating_add(x.ms_played);
+        }
+        s.into_iter().collect()
+    }
+
+    pub fn get_listening_by_day(&self) -> Vec<(NaiveDate, u64)> {
+        self.get_listening_by_day_in(&Utc)
+    }
+
+    pub fn get_listening_by_day_in<Tz: TimeZone>(&self, tz: &Tz) -> Vec<(NaiveDate, u64)> {
+        let mut s = BTreeMap::new();
+        for x in self.history.iter() {
+            let p = s
+                .entry(x.ts.with_timezone(tz).date_naive())
+                .or_insert(0_u64);
+            *p = p.saturating_add(x.ms_played);
+        }
+        s.into_iter().collect()
+    }
+
+    /// Returns the `n` days with the most listening, ties going to the
+    /// earlier day.
+    pub fn get_top_listening_days(&self, n: usize) -> Vec<(NaiveDate, u64)> {
+        self.get_top_listening_days_in(n, &Utc)
+    }
+
+    pub fn get_top_listening_days_in<Tz: TimeZone>(
+        &self,
+        n: usize,
+        tz: &Tz,
+    ) -> Vec<(NaiveDate, u64)> {
+        let mut r = self.get_listening_by_day_in(tz);
+        r.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        r.truncate(n);
+        r
+    }
+
+    /// Returns `(first_day, last_day, days)` for the longest run of
+    /// consecutive UTC days with at least `min_ms_per_day` of listening.
+    /// The earliest run wins a tie.
+    pub fn get_longest_listening_streak(
+        &self,
+        min_ms_per_day: u64,
+    ) -> Option<(NaiveDate, NaiveDate, u32)> {
+        let mut best: Option<(NaiveDate, NaiveDate, u32)> = None;
+        let mut current: Option<(NaiveDate, NaiveDate, u32)> = None;
+        for (day, ms) in self.get_listening_by_day() {
+            if ms < min_ms_per_day {
+                continue;
+            }
+            let run = match current {
+                Some((start, end, len)) if end.succ_opt() == Some(day) => (start, day, len + 1),
+                _ => (day, day, 1),
+            };
+            if best.is_none_or(|(_, _, len)| run.2 > len) {
+                best = Some(run);
+            }
+            current = Some(run);
+        }
+        best
+    }
+
+    /// Returns `(before, after, gap)` for the longest stretch between two
+    /// consecutive plays, or `None` with fewer than two plays. The earliest
+    /// gap wins a tie.
+    pub fn get_longest_gap(&self) -> Option<(DateTime<Utc>, DateTime<Utc>, Duration)> {
+        let mut ts: Vec<DateTime<Utc>> = self.history.iter().map(|x| x.ts).collect();
+        ts.sort_unstable();
+        ts.windows(2)
+            .map(|w| (w[0], w[1], w[1] - w[0]))
+            .fold(None, |best, gap| match best {
+                Some(best @ (_, _, longest)) if longest >= gap.2 => Some(best),
+                _ => Some(gap),
+            })
+    }
+
+    pub fn get_daily_play_stats(&self) -> (f64, u64) {
+        self.get_daily_play_stats_in(&Utc)
+    }
+
+    /// Returns `(average plays per active day, most plays in one day)`, with
+    /// days taken in `tz`. Days without plays don't lower the average.
+    pub fn get_daily_play_stats_in<Tz: TimeZone>(&self, tz: &Tz) -> (f64, u64) {
+        let mut s = HashMap::new();
+        for x in self.history.iter() {
+            *s.entry(x.ts.with_timezone(tz).date_naive())
+                .or_insert(0_u64) += 1;
+        }
+        if s.is_empty() {
+            return (0.0, 0);
+        }
+        let max = s.values().copied().max().unwrap_or(0);
+        (self.history.len() as f64 / s.len() as f64, max)
+    }
+
+    pub fn get_listening_by_hour(&self) -> [u64; 24] {
+        self.get_listening_by_hour_in(&Utc)
+    }
+
+    /// Like [`Self::get_listening_by_hour`], but buckets by the local hour in
+    /// `tz` (e.g. a `chrono_tz::Tz`).
+    pub fn get_listening_by_hour_in<Tz: TimeZone>(&self, tz: &Tz) -> [u64; 24] {
+        let mut r = [0_u64; 24];
+        for x in self.history.iter() {
+            let p = &mut r[x.ts.with_timezone(tz).hour() as usize];
+            *p = p.saturating_add(x.ms_played);
+        }
+        r
+    }
+
+    /// Share of ms_played in each local hour in `tz`, summing to 1.0, or all
+    /// zeros without any listening.
+    pub fn get_hourly_distribution<Tz: TimeZone>(&self, tz: &Tz) -> [f64; 24] {
+        let by_hour = self.get_listening_by_hour_in(tz);
+        let total = by_hour
+            .iter()
+            .fold(0_u64, |acc, ms| acc.saturating_add(*ms));
+        if total == 0 {
+            return [0.0; 24];
+        }
+        by_hour.map(|ms| ms as f64 / total as f64)
+    }
+
+    /// Fraction (0.0 to 1.0) of ms_played that fell between midnight and
+    /// 5am local time in `tz`, or 0.0 without any listening.
+    pub fn get_night_listening_ratio<Tz: TimeZone>(&self, tz: &Tz) -> f64 {
+        let by_hour = self.get_listening_by_hour_in(tz);
+        let total = by_hour
+            .iter()
+            .fold(0_u64, |acc, ms| acc.saturating_add(*ms));
+        if total == 0 {
+            return 0.0;
+        }
+        let night = by_hour[..5]
+            .iter()
+            .fold(0_u64, |acc, ms| acc.saturating_add(*ms));
+        night as f64 / total as f64
+    }
+
+    /// Groups plays into listening sessions, starting a new one whenever more
+    /// than `gap_minutes` pass between one play ending and the next starting.
+    /// Plays are placed by their start time, `ts - ms_played`; plays whose
+    /// start is out of range are left out.
+    pub fn get_session_stats(&self, gap_minutes: i64) -> SessionStats {
+        let mut plays: Vec<(DateTime<Utc>, DateTime<Utc>)> = self
+            .history
+            .iter()
+            .filter_map(|x| {
+                let ms = i64::try_from(x.ms_played).ok()?;
+                Some((x.ts.checked_sub_signed(Duration::milliseconds(ms))?, x.ts))
+            })
+            .collect();
+        plays.sort();
+
+        let gap = Duration::minutes(gap_minutes);
+        let mut sessions: Vec<(DateTime<Utc>, DateTime<Utc>)> = Vec::new();
+        for (start, end) in plays {
+            match sessions.last_mut() {
+                Some(s) if start - s.1 <= gap => s.1 = s.1.max(end),
+                _ => sessions.push((start, end)),
+            }
+        }
+
+        let lengths: Vec<u64> = sessions
+            .iter()
+            .map(|(start, end)| (*end - *start).num_milliseconds().max(0) as u64)
+            .collect();
+        let total = lengths
+            .iter()
+            .fold(0_u64, |acc, ms| acc.saturating_add(*ms));
+        SessionStats {
+            session_count: lengths.len(),
+            average_ms: total.checked_div(lengths.len() as u64).unwrap_or(0),
+            longest_ms: lengths.iter().copied().max().unwrap_or(0),
+        }
+    }
+
+    /// Returns `(offline, online)` total ms_played. Plays without an
+    /// `offline` flag count as online.
+    pub fn get_offline_split(&self) -> (u64, u64) {
+        let mut r = (0_u64, 0_u64);
+        for x in self.history.iter() {
+            let p = if x.offline == Some(true) {
+                &mut r.0
+            } else {
+                &mut r.1
+            };
+            *p = p.saturating_add(x.ms_played);
+        }
+        r
+    }
+
+    /// Returns `ms_played / duration_ms` for each track play whose duration
+    /// has been cached in the `track_duration` table. Values can exceed 1.0
+    /// when a track was scrubbed back over.
+    pub fn get_completion_ratio(&self) -> Vec<(&SpotifyHistoryEntry, f64)> {
+        self.history
+            .iter()
+            .filter_map(|x| {
+                let duration = *self.track_durations.get(x.spotify_track_uri.as_ref()?)?;
+                (duration > 0).then(|| (x, x.ms_played as f64 / duration as f64))
+            })
+            .collect()
+    }
+
+    fn count_plays_by<'a, F>(&'a self, key: F) -> Vec<(&'a str, u64)>
+    where
+        F: Fn(&'a SpotifyHistoryEntry) -> Option<&'a str>,
+    {
+        let mut s = HashMap::new();
+        for k in self.history.iter().filter_map(key) {
+            *s.entry(k).or_insert(0_u64) += 1;
+        }
+        let mut r: Vec<(&str, u64)> = s.into_iter().collect();
+        r.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+        r
+    }
+
+    pub fn get_reason_start_breakdown(&self) -> Vec<(&str, u64)> {
+        self.count_plays_by(|x| x.reason_start.as_deref())
+    }
+
+    pub fn get_reason_end_breakdown(&self) -> Vec<(&str, u64)> {
+        self.count_plays_by(|x| x.reason_end.as_deref())
+    }
+
+    /// Returns the fraction (0.0 to 1.0) of plays with a shuffle flag that
+    /// were on shuffle, or 0.0 when no play has the flag.
+    pub fn get_shuffle_percentage(&self) -> f64 {
+        let (shuffled, known) = self
+            .history
+            .iter()
+            .filter_map(|x| x.shuffle)
+            .fold((0_u64, 0_u64), |(s, n), shuffle| {
+                (s + shuffle as u64, n + 1)
+            });
+        if known == 0 {
+            return 0.0;
+        }
+        shuffled as f64 / known as f64
+    }
+
+    /// Returns `(skipped plays, plays with a skip flag, skipped fraction)`.
+    /// Older exports often leave `skipped` null, so only flagged plays count
+    /// towards the ratio, which is 0.0 when none are flagged.
+    pub fn get_overall_skip_ratio(&self) -> (u64, u64, f64) {
+        let (skipped, known) = self
+            .history
+            .iter()
+            .filter_map(|x| x.skipped)
+            .fold((0_u64, 0_u64), |(s, n), skipped| {
+                (s + skipped as u64, n + 1)
+            });
+        if known == 0 {
+            return (0, 0, 0.0);
+        }
+        (skipped, known, skipped as f64 / known as f64)
+    }
+
+    /// Returns `(play count, total ms_played)` of incognito plays.
+    pub fn get_incognito_stats(&self) -> (u64, u64) {
+        self.history
+            .iter()
+            .filter(|x| x.incognito_mode == Some(true))
+            .fold((0_u64, 0_u64), |(n, ms), x| {
+                (n + 1, ms.saturating_add(x.ms_played))
+            })
+    }
+
+    /// Returns `(weekday, weekend)` total ms_played, splitting Mon–Fri from
+    /// Sat–Sun in UTC.
+    pub fn get_weekday_weekend_split(&self) -> (u64, u64) {
+        self.get_weekday_weekend_split_in(&Utc)
+    }
+
+    pub fn get_weekday_weekend_split_in<Tz: TimeZone>(&self, tz: &Tz) -> (u64, u64) {
+        let mut r = (0_u64, 0_u64);
+        for x in self.history.iter() {
+            let p = match x.ts.with_timezone(tz).weekday() {
+                Weekday::Sat | Weekday::Sun => &mut r.1,
+                _ => &mut r.0,
+            };
+            *p = p.saturating_add(x.ms_played);
+        }
+        r
+    }
+
+    pub fn get_listening_by_weekday(&self) -> [u64; 7] {
+        self.get_listening_by_weekday_in(&Utc)
+    }
+
+    /// Total ms_played per local weekday in `tz`, indexed from Monday (`0`).
+    pub fn get_listening_by_weekday_in<Tz: TimeZone>(&self, tz: &Tz) -> [u64; 7] {
+        let mut r = [0_u64; 7];
+        for x in self.history.iter() {
+            let p = &mut r[x.ts.with_timezone(tz).weekday().num_days_from_monday() as usize];
+            *p = p.saturating_add(x.ms_played);
+        }
+        r
+    }
+
+    pub fn get_weekday_hour_matrix(&self) -> [[u64; 24]; 7] {
+        self.get_weekday_hour_matrix_in(&Utc)
+    }
+
+    /// Total ms_played per local `[weekday][hour]` in `tz`, with weekdays
+    /// indexed from Monday (`0`).
+    pub fn get_weekday_hour_matrix_in<Tz: TimeZone>(&self, tz: &Tz) -> [[u64; 24]; 7] {
+        let mut r = [[0_u64; 24]; 7];
+        for x in self.history.iter() {
+            let ts = x.ts.with_timezone(tz);
+            let p = &mut r[ts.weekday().num_days_from_monday() as usize][ts.hour() as usize];
+            *p = p.saturating_add(x.ms_played);
+        }
+        r
+    }
+
+    pub fn get_all_top_shows(&self) -> Vec<(&str, u64)> {
+        let mut s = HashMap::new();
+        for x in self.history.iter() {
+            if let Some(sh) = x.episode_show_name.as_ref() {
+                let p = s.entry(sh.as_str()).or_insert(0_u64);
+                *p = p.saturating_add(x.ms_played);
+            }
+        }
+        let mut r: Vec<(&str, u64)> = s.into_iter().collect();
+        r.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+        r
+    }
+
+    pub fn get_top_n_shows(&self, n: usize) -> Vec<(&str, u64)> {
+        self.get_all_top_shows().into_iter().take(n).collect()
+    }
+
+    /// Summarizes the plays in each of the inclusive ranges `a` and `b`,
+    /// along with the change from `a` to `b`.
+    pub fn compare_ranges(
+        &self,
+        a: (DateTime<Utc>, DateTime<Utc>),
+        b: (DateTime<Utc>, DateTime<Utc>),
+    ) -> RangeComparison {
+        let a = self.range_summary(a);
+        let b = self.range_summary(b);
+        let delta = |x: usize, y: usize| y as i64 - x as i64;
+        RangeComparison {
+            ms_played_delta: b.ms_played as i64 - a.ms_played as i64,
+            unique_artists_delta: delta(a.unique_artists, b.unique_artists),
+            unique_tracks_delta: delta(a.unique_tracks, b.unique_tracks),
+            a,
+            b,
+        }
+    }
+
+    fn range_summary(&self, (from, to): (DateTime<Utc>, DateTime<Utc>)) -> RangeSummary {
+        let range = self.filtered(from, to);
+        RangeSummary {
+            from,
+            to,
+            ms_played: range.total_ms_played(),
+            top_artist: range
+                .get_top_n_artists(1)
+                .first()
+                .map(|(artist, _)| artist.to_string()),
+            unique_artists: range.count_unique_artists(),
+            unique_tracks: range.count_unique_tracks(),
+        }
+    }
+}
+
+/// Aggregations computed in SQLite over the saved history, created by
+/// [`SpotifyAnalytics::read_only`].
+pub struct ReadOnlyAnalytics {
+    conn: Connection,
+}
+
+impl ReadOnlyAnalytics {
+    pub fn total_plays(&self) -> Result<u64> {
+        let n = self
+            .conn
+            .query_row("SELECT COUNT(*) FROM spotify_history", [], |r| r.get(0))?;
+        Ok(n)
+    }
+
+    pub fn total_ms_played(&self) -> Result<u64> {
+        let ms = self.conn.query_row(
+            "SELECT IFNULL(SUM(ms_played), 0) FROM spotify_history",
+            [],
+            |r| r.get(0),
+        )?;
+        Ok(ms)
+    }
+
+    /// First and last play, or `None` when the history is empty.
+    pub fn date_range(&self) -> Result<Option<(DateTime<Utc>, DateTime<Utc>)>> {
+        let bounds: (Option<DateTime<Utc>>, Option<DateTime<Utc>>) =
+            self.conn
+                .query_row("SELECT MIN(ts), MAX(ts) FROM spotify_history", [], |r| {
+                    Ok((r.get(0)?, r.get(1)?))
+                })?;
+        Ok(bounds.0.zip(bounds.1))
+    }
+
+    pub fn get_top_n_artists(&self, n: usize) -> Result<Vec<(String, u64)>> {
+        top_artists_sql(&self.conn, n)
+    }
+
+    pub fn get_top_n_tracks(&self, n: usize) -> Result<Vec<((String, String), u64)>> {
+        top_tracks_sql(&self.conn, n)
+    }
+
+    pub fn get_top_n_tracks_by_count(&self, n: usize) -> Result<Vec<((String, String), u64)>> {
+        top_tracks_by_count_sql(&self.conn, n)
+    }
+
+    pub fn get_top_n_albums(&self, n: usize) -> Result<Vec<((String, String), u64)>> {
+        top_albums_sql(&self.conn, n)
+    }
+
+    pub fn get_top_n_shows(&self, n: usize) -> Result<Vec<(String, u64)>> {
+        top_shows_sql(&self.conn, n)
+    }
+}
+
+/// A labeled date range, stored in the `annotations` table.
+#[derive(Debug, Clone, Serialize)]
+pub struct Annotation {
+    pub label: String,
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ArtistSummary {
+    pub name: String,
+    pub play_count: u64,
+    pub ms_played: u64,
+    pub distinct_tracks: usize,
+    pub first_play: DateTime<Utc>,
+    pub last_play: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RangeSummary {
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+    pub ms_played: u64,
+    pub top_artist: Option<String>,
+    pub unique_artists: usize,
+    pub unique_tracks: usize,
+}
+
+/// Two [`RangeSummary`]s and the change from `a` to `b`.
+#[derive(Debug, Clone, Serialize)]
+pub struct RangeComparison {
+    pub a: RangeSummary,
+    pub b: RangeSummary,
+    pub ms_played_delta: i64,
+    pub unique_artists_delta: i64,
+    pub unique_tracks_delta: i64,
+}
+
+/// What [`SpotifyAnalytics::save`] did with the incoming entries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct ImportReport {
+    /// Plays that were new to the database.
+    pub inserted: usize,
+    /// Plays already in the database, left untouched.
+    pub skipped_duplicates: usize,
+    /// Malformed entries dropped while importing with `skip_invalid`.
+    pub errors: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionStats {
+    pub session_count: usize,
+    pub average_ms: u64,
+    pub longest_ms: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(ts: &str, artist: &str, track: &str, ms_played: u64) -> SpotifyHistoryEntry {
+        SpotifyHistoryEntry {
+            ts: ts.parse().unwrap(),
+            username: Some("user".to_owned()),
+            platform: None,
+            ms_played,
+            conn_country: None,
+            ip_addr_decrypted: None,
+            user_agent_decrypted: None,
+            master_metadata_track_name: Some(track.to_owned()),
+            master_metadata_album_artist_name: Some(artist.to_owned()),
+            master_metadata_album_album_name: None,
+            spotify_track_uri: Some(format!("spotify:track:{track}")),
+            episode_name: None,
+            episode_show_name: None,
+            spotify_episode_uri: None,
+            reason_start: None,
+            reason_end: None,
+            shuffle: None,
+            skipped: None,
+            offline: None,
+            offline_timestamp: None,
+            incognito_mode: None,
+        }
+    }
+
+    fn episode(ts: &str, show: &str, ms_played: u64) -> SpotifyHistoryEntry {
+        SpotifyHistoryEntry {
+            master_metadata_track_name: None,
+            master_metadata_album_artist_name: None,
+            spotify_track_uri: None,
+            episode_name: Some(format!("{show} episode")),
+            episode_show_name: Some(show.to_owned()),
+            spotify_episode_uri: Some(format!("spotify:episode:{show}")),
+            ..entry(ts, "", "", ms_played)
+        }
+    }
+
+    fn analytics(history: Vec<SpotifyHistoryEntry>) -> SpotifyAnalytics {
+        SpotifyAnalytics::from_history(PathBuf::new(), history)
+    }
+
+    fn write_json(path: &Path, entries: &[SpotifyHistoryEntry]) {
+        fs::write(path, serde_json::to_vec(entries).unwrap()).unwrap();
+    }
+
+    fn count_rows(db_path: &Path) -> u64 {
+        get_db(db_path)
+            .unwrap()
+            .query_row("SELECT COUNT(*) FROM spotify_history", [], |r| r.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn save_inserts_entries_newer_than_existing_history() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("history.db");
+        let first = dir.path().join("first.json");
+        let second = dir.path().join("second.json");
+        write_json(
+            &first,
+            &[
+                entry("2020-01-01T10:00:00Z", "a", "x", 1000),
+                entry("2020-01-02T10:00:00Z", "a", "y", 1000),
+            ],
+        );
+        write_json(&second, &[entry("2021-01-01T10:00:00Z", "b", "z", 1000)]);
+
+        let mut analytics = SpotifyAnalytics::new(&db_path).unwrap();
+        analytics
+            .deserialize_extended_streaming_history_json(&first)
+            .unwrap();
+        analytics.save().unwrap();
+        assert_eq!(count_rows(&db_path), 2);
+
+        let mut analytics = SpotifyAnalytics::new(&db_path).unwrap();
+        analytics
+            .deserialize_extended_streaming_history_json(&second)
+            .unwrap();
+        analytics.save().unwrap();
+        assert_eq!(count_rows(&db_path), 3);
+    }
+
+    #[test]
+    fn save_inserts_entries_older_than_existing_history() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("history.db");
+        let newer = dir.path().join("newer.json");
+        let mixed = dir.path().join("mixed.json");
+        write_json(
+            &newer,
+            &[
+                entry("2020-01-01T10:00:00Z", "a", "x", 1000),
+                entry("2020-03-01T10:00:00Z", "a", "y", 1000),
+            ],
+        );
+        write_json(
+            &mixed,
+            &[
+                entry("2019-01-01T10:00:00Z", "b", "z", 1000),
+                entry("2020-02-01T10:00:00Z", "b", "z", 1000),
+                entry("2020-03-01T10:00:00Z", "a", "y", 1000),
+            ],
+        );
+
+        let mut analytics = SpotifyAnalytics::new(&db_path).unwrap();
+        analytics
+            .deserialize_extended_streaming_history_json(&newer)
+            .unwrap();
+        analytics.save().unwrap();
+
+        let mut analytics = SpotifyAnalytics::new(&db_path).unwrap();
+        analytics
+            .deserialize_extended_streaming_history_json(&mixed)
+            .unwrap();
+        analytics.save().unwrap();
+        assert_eq!(count_rows(&db_path), 4);
+    }
+
+    #[test]
+    fn count_new_entries_matches_save() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("history.db");
+        let first = dir.path().join("first.json");
+        let second = dir.path().join("second.json");
+        write_json(&first, &[entry("2020-01-01T10:00:00Z", "a", "x", 1000)]);
+        write_json(
+            &second,
+            &[
+                entry("2020-01-01T10:00:00Z", "a", "x", 1000),
+                entry("2020-01-02T10:00:00Z", "a", "y", 1000),
+                entry("2020-01-02T10:00:00Z", "a", "y", 1000),
+            ],
+        );
+        let mut analytics = SpotifyAnalytics::new(&db_path).unwrap();
+        analytics
+            .deserialize_extended_streaming_history_json(&first)
+            .unwrap();
+        analytics.save().unwrap();
+
+        let mut analytics = SpotifyAnalytics::new(&db_path).unwrap();
+        analytics
+            .deserialize_extended_streaming_history_json(&second)
+            .unwrap();
+        assert_eq!(analytics.count_new_entries().unwrap(), 1);
+        assert_eq!(count_rows(&db_path), 1);
+        analytics.save().unwrap();
+        assert_eq!(count_rows(&db_path), 2);
+    }
+
+    #[test]
+    fn folder_import_skips_files_older_than_last_import() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("history.db");
+        let export = dir.path().join("export");
+        fs::create_dir(&export).unwrap();
+        write_json(
+            &export.join("old.json"),
+            &[entry("2020-01-01T10:00:00Z", "a", "x", 1000)],
+        );
+
+        let mut analytics = SpotifyAnalytics::new(&db_path).unwrap();
+        analytics
+            .deserialize_extended_streaming_history_json_files_from_folder(&export)
+            .unwrap();
+        analytics.save().unwrap();
+
+        let mut analytics = SpotifyAnalytics::new(&db_path).unwrap();
+        assert!(analytics.last_import.is_some());
+        // Pretend the file predates the last import regardless of clock skew.
+        analytics.last_import = Some(Utc::now() + chrono::Duration::hours(1));
+        analytics
+            .deserialize_extended_streaming_history_json_files_from_folder(&export)
+            .unwrap();
+        assert!(analytics.incoming.is_empty());
+
+        analytics.set_force(true);
+        analytics
+            .deserialize_extended_streaming_history_json_files_from_folder(&export)
+            .unwrap();
+        assert_eq!(analytics.incoming.len(), 1);
+    }
+
+    #[test]
+    fn filtered_imports_keep_the_incremental_cutoff() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("history.db");
+        let export = dir.path().join("export");
+        fs::create_dir(&export).unwrap();
+        let as_user = |username: &str, ts: &str| SpotifyHistoryEntry {
+            username: Some(username.to_owned()),
+            ..entry(ts, "a", "x", 1000)
+        };
+        write_json(
+            &export.join("history.json"),
+            &[
+                as_user("alice", "2020-01-01T10:00:00Z"),
+                as_user("bob", "2020-01-02T10:00:00Z"),
+            ],
+        );
+
+        let mut analytics = SpotifyAnalytics::new(&db_path).unwrap();
+        analytics.set_username("alice".to_owned());
+        analytics.import_path(&export).unwrap();
+        analytics.save().unwrap();
+
+        let mut analytics = SpotifyAnalytics::new(&db_path).unwrap();
+        assert!(analytics.last_import.is_none());
+        analytics.import_path(&export).unwrap();
+        analytics.drop_incoming_before("2020-01-02T00:00:00Z".parse().unwrap());
+        analytics.save().unwrap();
+        assert!(SpotifyAnalytics::new(&db_path)
+            .unwrap()
+            .last_import
+            .is_none());
+
+        let mut analytics = SpotifyAnalytics::new(&db_path).unwrap();
+        analytics.set_username("bob".to_owned());
+        analytics.import_path(&export).unwrap();
+        assert_eq!(analytics.incoming.len(), 1);
+    }
+
+    #[test]
+    fn dedup_incoming_removes_plays_seen_in_several_files() {
+        let mut analytics = analytics(vec![]);
+        analytics.incoming = vec![
+            entry("2020-01-01T10:00:00Z", "a", "x", 1000),
+            entry("2020-01-02T10:00:00Z", "a", "x", 1000),
+            entry("2020-01-01T10:00:00Z", "a", "x", 1000),
+        ];
+        assert_eq!(analytics.dedup_incoming(), 1);
+        assert_eq!(analytics.incoming.len(), 2);
+    }
+
+    #[test]
+    fn drop_incoming_before_keeps_plays_at_the_cutoff() {
+        let mut analytics = analytics(vec![]);
+        analytics.incoming = vec![
+            entry("2020-01-01T10:00:00Z", "a", "x", 1000),
+            entry("2020-01-02T10:00:00Z", "a", "y", 1000),
+            entry("2020-01-03T10:00:00Z", "a", "z", 1000),
+        ];
+        let since = Utc.with_ymd_and_hms(2020, 1, 2, 10, 0, 0).unwrap();
+        assert_eq!(analytics.drop_incoming_before(since), 1);
+        assert_eq!(analytics.incoming.len(), 2);
+    }
+
+    #[test]
+    fn dedup_sql_keeps_plays_of_other_users() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("history.db");
+        let mut analytics = SpotifyAnalytics::new(&db_path).unwrap();
+        let as_user = |username: &str| SpotifyHistoryEntry {
+            username: Some(username.to_owned()),
+            ..entry("2020-01-01T10:00:00Z", "a", "x", 1000)
+        };
+        analytics.incoming = vec![as_user("alice"), as_user("bob")];
+        analytics.save().unwrap();
+
+        let mut conn = get_db(&db_path).unwrap();
+        assert_eq!(count_duplicates_sql(&conn).unwrap(), 0);
+        assert_eq!(delete_duplicates_sql(&mut conn).unwrap(), 0);
+        assert_eq!(count_rows(&db_path), 2);
+    }
+
+    #[test]
+    fn dedup_reports_duplicates_from_before_the_unique_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("history.db");
+        let mut conn = Connection::open(&db_path).unwrap();
+        migrations().to_version(&mut conn, 1).unwrap();
+        for _ in 0..3 {
+            conn.execute(
+                "INSERT INTO spotify_history (ts, ms_played, spotify_track_uri)
+                VALUES ('2020-01-01T10:00:00Z', 1000, 'spotify:track:x')",
+                [],
+            )
+            .unwrap();
+        }
+        drop(conn);
+
+        let timeout = StdDuration::from_secs(DEFAULT_BUSY_TIMEOUT_SECS);
+        let conn = get_db_unmigrated(&db_path, timeout).unwrap();
+        assert_eq!(count_duplicates_sql(&conn).unwrap(), 2);
+        drop(conn);
+        // Reopening proves the report left the rows alone.
+        let mut conn = get_db_unmigrated(&db_path, timeout).unwrap();
+        assert_eq!(count_duplicates_sql(&conn).unwrap(), 2);
+        assert_eq!(delete_duplicates_sql(&mut conn).unwrap(), 2);
+        drop(conn);
+        assert_eq!(count_rows(&db_path), 1);
+    }
+
+    #[test]
+    fn reset_db_empties_tables() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("history.db");
+        let mut analytics = SpotifyAnalytics::new(&db_path).unwrap();
+        analytics.incoming = vec![entry("2020-01-01T10:00:00Z", "a", "x", 1000)];
+        analytics.save().unwrap();
+
+        assert_eq!(
+            reset_db(&db_path, StdDuration::from_secs(DEFAULT_BUSY_TIMEOUT_SECS)).unwrap(),
+            1
+        );
+        assert_eq!(count_rows(&db_path), 0);
+        assert!(SpotifyAnalytics::new(&db_path)
+            .unwrap()
+            .last_import
+            .is_none());
+    }
+
+    #[test]
+    fn get_db_reports_a_locked_database() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("history.db");
+        let holder = Connection::open(&db_path).unwrap();
+        holder.pragma_update(None, "journal_mode", "WAL").unwrap();
+        holder
+            .execute_batch("BEGIN EXCLUSIVE; CREATE TABLE held (x);")
+            .unwrap();
+
+        let r = get_db_with_busy_timeout(&db_path, StdDuration::from_millis(50));
+        assert!(matches!(r, Err(AnalyticsError::Locked { .. })));
+
+        holder.execute_batch("ROLLBACK").unwrap();
+        assert!(get_db(&db_path).is_ok());
+    }
+
+    #[test]
+    fn statements_on_a_locked_database_report_locked() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("history.db");
+        let mut analytics =
+            SpotifyAnalytics::with_busy_timeout(&db_path, StdDuration::from_millis(50)).unwrap();
+        analytics.incoming = vec![entry("2020-01-01T10:00:00Z", "a", "x", 1000)];
+        let mut conn = get_db_with_busy_timeout(&db_path, StdDuration::from_millis(50)).unwrap();
+        let holder = get_db(&db_path).unwrap();
+        holder.execute_batch("BEGIN EXCLUSIVE").unwrap();
+
+        assert!(matches!(
+            delete_range_sql(&mut conn, None, None, None),
+            Err(AnalyticsError::Locked { .. })
+        ));
+        assert!(matches!(
+            analytics.save(),
+            Err(AnalyticsError::Save { source, .. }) if matches!(*source, AnalyticsError::Locked { .. })
+        ));
+        holder.execute_batch("ROLLBACK").unwrap();
+    }
+
+    #[test]
+    fn listening_by_annotation_counts_overlaps_in_each() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("history.db");
+        let mut analytics = SpotifyAnalytics::new(&db_path).unwrap();
+        analytics.incoming = vec![
+            entry("2020-01-01T10:00:00Z", "a", "x", 1000),
+            entry("2020-01-05T10:00:00Z", "a", "y", 2000),
+            entry("2020-01-09T10:00:00Z", "a", "z", 4000),
+        ];
+        analytics.save().unwrap();
+        let day = |d| Utc.with_ymd_and_hms(2020, 1, d, 0, 0, 0).unwrap();
+        let conn = get_db(&db_path).unwrap();
+        add_annotation_sql(&conn, "trip", day(1), day(6)).unwrap();
+        add_annotation_sql(&conn, "exams", day(5), day(10)).unwrap();
+        add_annotation_sql(&conn, "empty", day(20), day(21)).unwrap();
+
+        let analytics = SpotifyAnalytics::new(&db_path).unwrap();
+        assert_eq!(
+            analytics.get_listening_by_annotation(),
+            vec![
+                ("exams".to_owned(), 6000),
+                ("trip".to_owned(), 3000),
+                ("empty".to_owned(), 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn delete_range_sql_is_inclusive() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("history.db");
+        let mut analytics = SpotifyAnalytics::new(&db_path).unwrap();
+        analytics.incoming = vec![
+            entry("2020-01-01T10:00:00Z", "a", "x", 1000),
+            entry("2020-01-02T10:00:00Z", "a", "x", 1000),
+            entry("2020-01-03T10:00:00Z", "a", "x", 1000),
+        ];
+        analytics.save().unwrap();
+
+        let mut conn = get_db(&db_path).unwrap();
+        let from = "2020-01-01T10:00:00Z".parse().ok();
+        let to = "2020-01-02T10:00:00Z".parse().ok();
+        assert_eq!(count_range_sql(&conn, None, to, None).unwrap(), 2);
+        assert_eq!(count_range_sql(&conn, to, None, None).unwrap(), 2);
+        assert_eq!(delete_range_sql(&mut conn, from, to, None).unwrap(), 2);
+        assert_eq!(count_rows(&db_path), 1);
+    }
+
+    #[test]
+    fn delete_range_sql_keeps_other_users() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("history.db");
+        let mut analytics = SpotifyAnalytics::new(&db_path).unwrap();
+        let as_user = |username: &str, ts: &str| SpotifyHistoryEntry {
+            username: Some(username.to_owned()),
+            ..entry(ts, "a", "x", 1000)
+        };
+        analytics.incoming = vec![
+            as_user("alice", "2020-01-01T10:00:00Z"),
+            as_user("alice", "2020-01-02T10:00:00Z"),
+            as_user("bob", "2020-01-01T10:00:00Z"),
+        ];
+        analytics.save().unwrap();
+
+        let mut conn = get_db(&db_path).unwrap();
+        let to = "2020-01-01T23:00:00Z".parse().ok();
+        assert_eq!(count_range_sql(&conn, None, to, Some("alice")).unwrap(), 1);
+        assert_eq!(
+            delete_range_sql(&mut conn, None, to, Some("alice")).unwrap(),
+            1
+        );
+        assert_eq!(count_range_sql(&conn, None, None, Some("bob")).unwrap(), 1);
+        assert_eq!(count_rows(&db_path), 2);
+    }
+
+    #[test]
+    fn save_commits_in_batches() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("history.db");
+        let mut analytics = SpotifyAnalytics::new(&db_path).unwrap();
+        analytics.incoming = (0..5)
+            .map(|i| entry(&format!("2020-01-0{}T10:00:00Z", i + 1), "a", "x", 1000))
+            .collect();
+        analytics.set_batch_size(2);
+        analytics.save().unwrap();
+        assert_eq!(count_rows(&db_path), 5);
+        assert!(SpotifyAnalytics::new(&db_path)
+            .unwrap()
+            .last_import
+            .is_some());
+    }
+
+    #[test]
+    fn failed_save_reports_rows_of_committed_batches() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("history.db");
+        let mut analytics = SpotifyAnalytics::new(&db_path).unwrap();
+        analytics.incoming = vec![entry("2020-01-01T10:00:00Z", "a", "x", 1000)];
+        analytics.save().unwrap();
+
+        get_db(&db_path)
+            .unwrap()
+            .execute_batch(
+                "CREATE TRIGGER reject BEFORE INSERT ON spotify_history
+                WHEN NEW.ms_played = 666 BEGIN SELECT RAISE(ABORT, 'rejected'); END;",
+            )
+            .unwrap();
+        // The first batch holds an already saved play and a new one, the
+        // second fails on the trigger.
+        analytics.incoming = vec![
+            entry("2020-01-01T10:00:00Z", "a", "x", 1000),
+            entry("2020-01-02T10:00:00Z", "a", "y", 1000),
+            entry("2020-01-03T10:00:00Z", "a", "z", 666),
+        ];
+        analytics.set_batch_size(2);
+        let r = analytics.save();
+        assert!(matches!(r, Err(AnalyticsError::Save { committed: 2, .. })));
+        assert_eq!(count_rows(&db_path), 2);
+    }
+
+    #[test]
+    fn save_ignores_duplicate_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("history.db");
+        let file = dir.path().join("history.json");
+        write_json(
+            &file,
+            &[
+                entry("2020-01-01T10:00:00Z", "a", "x", 1000),
+                entry("2020-01-02T10:00:00Z", "a", "y", 1000),
+                episode("2020-01-03T10:00:00Z", "show", 1000),
+            ],
+        );
+
+        let mut analytics = SpotifyAnalytics::new(&db_path).unwrap();
+        analytics
+            .deserialize_extended_streaming_history_json(&file)
+            .unwrap();
+        analytics
+            .deserialize_extended_streaming_history_json(&file)
+            .unwrap();
+        let report = analytics.save().unwrap();
+        assert_eq!(count_rows(&db_path), 3);
+        assert_eq!((report.inserted, report.skipped_duplicates), (3, 3));
+
+        let mut analytics = SpotifyAnalytics::new(&db_path).unwrap();
+        analytics
+            .deserialize_extended_streaming_history_json(&file)
+            .unwrap();
+        let report = analytics.save().unwrap();
+        assert_eq!(count_rows(&db_path), 3);
+        assert_eq!(
+            report,
+            ImportReport {
+                inserted: 0,
+                skipped_duplicates: 3,
+                errors: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn top_shows_ignore_music_plays() {
+        let analytics = analytics(vec![
+            entry("2020-01-01T10:00:00Z", "a", "x", 5000),
+            episode("2020-01-01T11:00:00Z", "s1", 1000),
+            episode("2020-01-01T12:00:00Z", "s1", 2000),
+            episode("2020-01-01T13:00:00Z", "s2", 4000),
+        ]);
+        assert_eq!(
+            analytics.get_all_top_shows(),
+            vec![("s2", 4000), ("s1", 3000)]
+        );
+        assert_eq!(analytics.get_top_n_shows(1), vec![("s2", 4000)]);
+    }
+
+    #[test]
+    fn deserialize_rejects_non_array_json() {
+        let mut analytics = analytics(vec![]);
+        assert!(analytics
+            .deserialize_extended_streaming_history_json_reader(&b"{}"[..])
+            .is_err());
+    }
+
+    #[test]
+    fn deserialize_reads_json_lines() {
+        let lines: Vec<String> = [
+            entry("2020-01-01T10:00:00Z", "a", "x", 1000),
+            entry("2020-01-01T11:00:00Z", "b", "y", 2000),
+        ]
+        .iter()
+        .map(|e| serde_json::to_string(e).unwrap())
+        .collect();
+        let ndjson = format!("\n{}\n", lines.join("\n"));
+
+        let mut analytics = analytics(vec![]);
+        analytics
+            .deserialize_extended_streaming_history_json_reader(ndjson.as_bytes())
+            .unwrap();
+        let played: Vec<u64> = analytics.incoming.iter().map(|x| x.ms_played).collect();
+        assert_eq!(played, vec![1000, 2000]);
+    }
+
+    #[test]
+    fn video_history_files_are_saved_separately() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("history.db");
+        write_json(
+            &dir.path().join("Streaming_History_Audio_2020.json"),
+            &[entry("2020-01-01T10:00:00Z", "a", "x", 1000)],
+        );
+        fs::write(
+            dir.path().join("Streaming_History_Video_2020.json"),
+            r#"[{"ts": "2020-01-02T10:00:00Z", "ms_played": 5000, "episode_show_name": "s"}]"#,
+        )
+        .unwrap();
+
+        let mut analytics = SpotifyAnalytics::new(&db_path).unwrap();
+        analytics.import_path(dir.path()).unwrap();
+        assert_eq!(analytics.incoming.len(), 1);
+        assert_eq!(analytics.incoming_video.len(), 1);
+        analytics.save().unwrap();
+
+        assert_eq!(count_rows(&db_path), 1);
+        let videos: u64 = get_db(&db_path)
+            .unwrap()
+            .query_row("SELECT COUNT(*) FROM video_history", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(videos, 1);
+    }
+
+    #[test]
+    fn username_filter_applies_to_video_plays() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("Streaming_History_Video_2020.json");
+        fs::write(
+            &file,
+            r#"[
+                {"ts": "2020-01-02T10:00:00Z", "username": "alice", "ms_played": 1},
+                {"ts": "2020-01-02T10:00:00Z", "username": "bob", "ms_played": 1}
+            ]"#,
+        )
+        .unwrap();
+
+        let mut analytics = analytics(vec![]);
+        analytics.set_username("alice".to_owned());
+        analytics.import_path(&file).unwrap();
+        let usernames: Vec<_> = analytics
+            .incoming_video
+            .iter()
+            .map(|x| x.username.as_deref())
+            .collect();
+        assert_eq!(usernames, vec![Some("alice")]);
+    }
+
+    #[test]
+    fn folder_import_recurses_into_subdirectories() {
+        let dir = tempfile::tempdir().unwrap();
+        let nested = dir.path().join("a").join("b");
+        fs::create_dir_all(&nested).unwrap();
+        write_json(
+            &dir.path().join("top.json"),
+            &[entry("2020-01-01T10:00:00Z", "a", "x", 1000)],
+        );
+        write_json(
+            &dir.path().join("a").join("mid.json"),
+            &[entry("2020-01-02T10:00:00Z", "a", "y", 1000)],
+        );
+        write_json(
+            &nested.join("deep.json"),
+            &[entry("2020-01-03T10:00:00Z", "a", "z", 1000)],
+        );
+        fs::write(nested.join("ReadMe.txt"), "not json").unwrap();
+
+        let mut analytics = analytics(vec![]);
+        analytics
+            .deserialize_extended_streaming_history_json_files_from_folder(dir.path())
+            .unwrap();
+        let mut tracks: Vec<_> = analytics
+            .incoming
+            .iter()
+            .filter_map(|x| x.master_metadata_track_name.as_deref())
+            .collect();
+        tracks.sort();
+        assert_eq!(tracks, vec!["x", "y", "z"]);
+    }
+
+    #[test]
+    fn gzipped_json_is_decompressed() {
+        use flate2::write::GzEncoder;
+        use std::io::Write;
+
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("Streaming_History_Audio_2020.json.gz");
+        let mut gz = GzEncoder::new(File::create(&file).unwrap(), Default::default());
+        gz.write_all(
+            &serde_json::to_vec(&[entry("2020-01-01T10:00:00Z", "a", "x", 1000)]).unwrap(),
+        )
+        .unwrap();
+        gz.finish().unwrap();
+        write_json(
+            &dir.path().join("plain.json"),
+            &[entry("2020-01-02T10:00:00Z", "a", "y", 1000)],
+        );
+
+        let mut single = analytics(vec![]);
+        single.import_path(&file).unwrap();
+        assert_eq!(single.incoming.len(), 1);
+
+        let mut folder = analytics(vec![]);
+        folder.import_path(dir.path()).unwrap();
+        let mut tracks: Vec<_> = folder
+            .incoming
+            .iter()
+            .filter_map(|x| x.master_metadata_track_name.as_deref())
+            .collect();
+        tracks.sort();
+        assert_eq!(tracks, vec!["x", "y"]);
+    }
+
+    #[test]
+    fn tar_gz_import_reads_matching_members() {
+        use flate2::write::GzEncoder;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("my_spotify_data.tar.gz");
+        let gz = GzEncoder::new(File::create(&path).unwrap(), Default::default());
+        let mut tar = tar::Builder::new(gz);
+        let mut append = |name: &str, data: &[u8]| {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            tar.append_data(&mut header, name, data).unwrap();
+        };
+        let history = serde_json::to_vec(&[entry("2020-01-01T10:00:00Z", "a", "x", 1000)]).unwrap();
+        append(
+            "Spotify Extended Streaming History/Streaming_History_Audio_2020.json",
+            &history,
+        );
+        append(
+            "Spotify Extended Streaming History/ReadMeFirst.pdf",
+            b"not json",
+        );
+        tar.into_inner().unwrap().finish().unwrap();
+
+        let mut analytics = analytics(vec![]);
+        analytics.import_path(&path).unwrap();
+        assert_eq!(analytics.incoming.len(), 1);
+    }
+
+    #[test]
+    fn listening_by_hour_buckets_by_local_hour() {
+        let analytics = analytics(vec![
+            entry("2020-01-01T00:30:00Z", "a", "x", 1000),
+            entry("2020-01-01T07:59:59Z", "a", "y", 2000),
+            entry("2020-01-02T07:00:00Z", "a", "z", 3000),
+            entry("2020-01-01T23:00:00Z", "a", "x", 4000),
+        ]);
+
+        let utc = analytics.get_listening_by_hour();
+        assert_eq!(utc[0], 1000);
+        assert_eq!(utc[7], 5000);
+        assert_eq!(utc[23], 4000);
+        assert_eq!(utc.iter().sum::<u64>(), 10000);
+
+        let tokyo = analytics.get_listening_by_hour_in(&chrono_tz::Asia::Tokyo);
+        assert_eq!(tokyo[9], 1000);
+        assert_eq!(tokyo[16], 5000);
+        assert_eq!(tokyo[8], 4000);
+    }
+
+    #[test]
+    fn hourly_distribution_sums_to_one() {
+        assert_eq!(analytics(vec![]).get_hourly_distribution(&Utc), [0.0; 24]);
+        let analytics = analytics(vec![
+            entry("2020-01-01T10:00:00Z", "a", "x", 3000),
+            entry("2020-01-01T22:00:00Z", "a", "x", 1000),
+        ]);
+        let r = analytics.get_hourly_distribution(&chrono_tz::Asia::Tokyo);
+        assert_eq!(r[19], 0.75);
+        assert_eq!(r[7], 0.25);
+        assert_eq!(r.iter().sum::<f64>(), 1.0);
+    }
+
+    #[test]
+    fn filtered_keeps_inclusive_range() {
+        let analytics = analytics(vec![
+            entry("2019-12-31T23:59:59Z", "a", "x", 1000),
+            entry("2020-01-01T00:00:00Z", "b", "x", 1000),
+            entry("2020-12-31T23:59:59Z", "c", "x", 1000),
+            entry("2021-01-01T00:00:00Z", "d", "x", 1000),
+        ]);
+        let filtered = analytics.filtered(
+            "2020-01-01T00:00:00Z".parse().unwrap(),
+            "2020-12-31T23:59:59Z".parse().unwrap(),
+        );
+        let mut artists: Vec<_> = filtered
+            .get_all_top_artists()
+            .into_iter()
+            .map(|(a, _)| a)
+            .collect();
+        artists.sort();
+        assert_eq!(artists, vec!["b", "c"]);
+        assert_eq!(
+            filtered.date_range(),
+            Some((
+                "2020-01-01T00:00:00Z".parse().unwrap(),
+                "2020-12-31T23:59:59Z".parse().unwrap()
+            ))
+        );
+        let empty = analytics.filtered(
+            "2022-01-01T00:00:00Z".parse().unwrap(),
+            "2022-12-31T23:59:59Z".parse().unwrap(),
+        );
+        assert_eq!(empty.date_range(), None);
+    }
+
+    #[test]
+    fn deserialize_falls_back_to_basic_schema() {
+        let data = br#"[
+            {"endTime": "2021-03-04 05:06", "artistName": "a", "trackName": "x", "msPlayed": 1234},
+            {"ts": "2021-03-04T06:00:00Z", "ms_played": 10, "master_metadata_track_name": "y"}
+        ]"#;
+        let mut analytics = analytics(vec![]);
+        analytics
+            .deserialize_extended_streaming_history_json_reader(&data[..])
+            .unwrap();
+
+        let basic = &analytics.incoming[0];
+        assert_eq!(
+            basic.ts,
+            "2021-03-04T05:06:00Z".parse::<DateTime<Utc>>().unwrap()
+        );
+        assert_eq!(basic.ms_played, 1234);
+        assert_eq!(
+            basic.master_metadata_album_artist_name.as_deref(),
+            Some("a")
+        );
+        assert_eq!(basic.master_metadata_track_name.as_deref(), Some("x"));
+        assert_eq!(
+            analytics.incoming[1].master_metadata_track_name.as_deref(),
+            Some("y")
+        );
+    }
+
+    #[test]
+    fn sql_top_n_matches_in_memory_aggregation() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("history.db");
+        let file = dir.path().join("history.json");
+        write_json(
+            &file,
+            &[
+                entry("2020-01-01T10:00:00Z", "a", "x", 1000),
+                entry("2020-01-02T10:00:00Z", "a", "y", 3000),
+                entry("2020-01-03T10:00:00Z", "b", "x", 2500),
+                episode("2020-01-04T10:00:00Z", "s", 500),
+                // Ties with "a", so both paths must break ties by name.
+                entry("2020-01-05T10:00:00Z", "c", "w", 4000),
+            ],
+        );
+        let mut analytics = SpotifyAnalytics::new(&db_path).unwrap();
+        analytics
+            .deserialize_extended_streaming_history_json(&file)
+            .unwrap();
+        analytics.save().unwrap();
+
+        let analytics = SpotifyAnalytics::new(&db_path).unwrap();
+        let conn = get_db(&db_path).unwrap();
+        let owned = |v: Vec<(&str, u64)>| -> Vec<(String, u64)> {
+            v.into_iter().map(|(a, ms)| (a.to_owned(), ms)).collect()
+        };
+        assert_eq!(
+            top_artists_sql(&conn, 10).unwrap(),
+            owned(analytics.get_top_n_artists(10))
+        );
+        assert_eq!(
+            top_shows_sql(&conn, 10).unwrap(),
+            owned(analytics.get_top_n_shows(10))
+        );
+        let tracks: Vec<((String, String), u64)> = analytics
+            .get_top_n_tracks(2)
+            .into_iter()
+            .map(|((t, a), ms)| ((t.to_owned(), a.to_owned()), ms))
+            .collect();
+        assert_eq!(top_tracks_sql(&conn, 2).unwrap(), tracks);
+        let tracks: Vec<((String, String), u64)> = analytics
+            .get_most_repeated_tracks(10)
+            .into_iter()
+            .map(|((t, a), n)| ((t.to_owned(), a.to_owned()), n))
+            .collect();
+        assert_eq!(top_tracks_by_count_sql(&conn, 10).unwrap(), tracks);
+    }
+
+    #[test]
+    fn listening_by_country_groups_codes_and_skips_none() {
+        let in_country = |country: Option<&str>, ms_played| SpotifyHistoryEntry {
+            conn_country: country.map(str::to_owned),
+            ..entry("2020-01-01T10:00:00Z", "a", "x", ms_played)
+        };
+        let analytics = analytics(vec![
+            in_country(Some("US"), 1000),
+            in_country(Some("JP"), 500),
+            in_country(Some("US"), 2000),
+            in_country(None, 9000),
+        ]);
+        assert_eq!(
+            analytics.get_listening_by_country(),
+            vec![("US", 3000), ("JP", 500)]
+        );
+    }
+
+    #[test]
+    fn strict_rejects_implausible_ms_played() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history.json");
+        let mut done = entry("2020-01-01T10:00:00Z", "a", "x", 0);
+        done.reason_end = Some("trackdone".to_owned());
+        write_json(&path, &[done]);
+
+        let mut lenient = analytics(vec![]);
+        lenient.import_path(&path).unwrap();
+        assert_eq!(lenient.incoming.len(), 1);
+
+        let mut strict = analytics(vec![]);
+        strict.set_strict(true);
+        assert!(strict.import_path(&path).is_err());
+    }
+
+    #[test]
+    fn skip_invalid_drops_malformed_entries() {
+        let data = br#"[
+            {"ts": "2021-03-04T06:00:00Z", "ms_played": 10},
+            {"ts": "2021-03-04T07:00:00Z", "ms_played": "oops"},
+            {"ts": "2021-03-04T08:00:00Z", "ms_played": 30}
+        ]"#;
+
+        let mut strict = analytics(vec![]);
+        assert!(strict
+            .deserialize_extended_streaming_history_json_reader(&data[..])
+            .is_err());
+
+        let mut lenient = analytics(vec![]);
+        lenient.set_skip_invalid(true);
+        lenient
+            .deserialize_extended_streaming_history_json_reader(&data[..])
+            .unwrap();
+        let played: Vec<u64> = lenient.incoming.iter().map(|x| x.ms_played).collect();
+        assert_eq!(played, vec![10, 30]);
+    }
+
+    #[test]
+    fn weekday_weekend_split_uses_local_weekday() {
+        let analytics = analytics(vec![
+            // Friday
+            entry("2021-01-01T12:00:00Z", "a", "x", 1000),
+            // Saturday
+            entry("2021-01-02T12:00:00Z", "a", "x", 2000),
+            // Sunday, but already Monday in Tokyo
+            entry("2021-01-03T20:00:00Z", "a", "x", 4000),
+            // Monday
+            entry("2021-01-04T12:00:00Z", "a", "x", 8000),
+        ]);
+        assert_eq!(analytics.get_weekday_weekend_split(), (9000, 6000));
+        assert_eq!(
+            analytics.get_weekday_weekend_split_in(&chrono_tz::Asia::Tokyo),
+            (13000, 2000)
+        );
+    }
+
+    #[test]
+    fn listening_by_weekday_starts_on_monday() {
+        let analytics = analytics(vec![
+            // Monday, Wednesday and Sunday in UTC.
+            entry("2020-01-06T10:00:00Z", "a", "x", 1000),
+            entry("2020-01-08T10:00:00Z", "a", "x", 2000),
+            entry("2020-01-12T10:00:00Z", "a", "x", 4000),
+            // Sunday in UTC, Monday in Tokyo.
+            entry("2020-01-12T20:00:00Z", "a", "x", 8000),
+        ]);
+        assert_eq!(
+            analytics.get_listening_by_weekday(),
+            [1000, 0, 2000, 0, 0, 0, 12000]
+        );
+        assert_eq!(
+            analytics.get_listening_by_weekday_in(&chrono_tz::Asia::Tokyo),
+            [9000, 0, 2000, 0, 0, 0, 4000]
+        );
+    }
+
+    #[test]
+    fn longest_streak_stops_at_gaps_and_short_days() {
+        let analytics = analytics(vec![
+            entry("2020-01-01T10:00:00Z", "a", "x", 60_000),
+            entry("2020-01-02T10:00:00Z", "a", "x", 60_000),
+            entry("2020-01-04T10:00:00Z", "a", "x", 60_000),
+            entry("2020-01-05T10:00:00Z", "a", "x", 60_000),
+            entry("2020-01-06T10:00:00Z", "a", "x", 60_000),
+            entry("2020-01-07T10:00:00Z", "a", "x", 1_000),
+        ]);
+        let day = |d| NaiveDate::from_ymd_opt(2020, 1, d).unwrap();
+        assert_eq!(
+            analytics.get_longest_listening_streak(30_000),
+            Some((day(4), day(6), 3))
+        );
+        assert_eq!(
+            analytics.get_longest_listening_streak(0),
+            Some((day(4), day(7), 4))
+        );
+        assert_eq!(analytics.get_longest_listening_streak(u64::MAX), None);
+    }
+
+    #[test]
+    fn longest_gap_is_between_sorted_plays() {
+        let day = |d| Utc.with_ymd_and_hms(2020, 1, d, 10, 0, 0).unwrap();
+        let analytics = analytics(vec![
+            entry("2020-01-20T10:00:00Z", "a", "x", 1000),
+            entry("2020-01-01T10:00:00Z", "a", "x", 1000),
+            entry("2020-01-05T10:00:00Z", "a", "x", 1000),
+            entry("2020-01-12T10:00:00Z", "a", "x", 1000),
+        ]);
+        assert_eq!(
+            analytics.get_longest_gap(),
+            Some((day(12), day(20), Duration::days(8)))
+        );
+        assert_eq!(analytics.filtered(day(1), day(1)).get_longest_gap(), None);
+    }
+
+    #[test]
+    fn normalized_top_artists_merge_case_and_whitespace() {
+        let analytics = analytics(vec![
+            entry("2020-01-01T10:00:00Z", "Radiohead", "x", 3000),
+            entry("2020-01-01T11:00:00Z", "radiohead ", "y", 2000),
+            entry("2020-01-01T12:00:00Z", "Muse", "z", 4000),
+        ]);
+        assert_eq!(
+            analytics.get_all_top_artists_normalized(),
+            vec![("Radiohead", 5000), ("Muse", 4000)]
+        );
+        assert_eq!(analytics.get_all_top_artists().len(), 3);
+    }
+
+    #[test]
+    fn session_stats_split_on_gaps() {
+        let analytics = analytics(vec![
+            // 10:00-10:05 and 10:06-10:10 form one session.
+            entry("2020-01-01T10:05:00Z", "a", "x", 300_000),
+            entry("2020-01-01T10:10:00Z", "a", "y", 240_000),
+            // An hour later, a second session of 2 minutes.
+            entry("2020-01-01T11:12:00Z", "a", "z", 120_000),
+        ]);
+        let stats = analytics.get_session_stats(30);
+        assert_eq!(stats.session_count, 2);
+        assert_eq!(stats.longest_ms, 600_000);
+        assert_eq!(stats.average_ms, 360_000);
+        assert_eq!(analytics.get_session_stats(120).session_count, 1);
+    }
+
+    #[test]
+    fn session_stats_skip_out_of_range_plays() {
+        let analytics = analytics(vec![
+            entry("2020-01-01T10:05:00Z", "a", "x", 300_000),
+            entry("2020-01-01T10:10:00Z", "a", "y", u64::MAX),
+            entry("2020-01-01T10:15:00Z", "a", "z", i64::MAX as u64),
+        ]);
+        let stats = analytics.get_session_stats(30);
+        assert_eq!(stats.session_count, 1);
+        assert_eq!(stats.longest_ms, 300_000);
+    }
+
+    #[test]
+    fn one_play_artists_are_ordered_by_play_date() {
+        let analytics = analytics(vec![
+            entry("2020-01-03T10:00:00Z", "b", "x", 1000),
+            entry("2020-01-01T10:00:00Z", "a", "x", 1000),
+            entry("2020-01-02T10:00:00Z", "a", "y", 1000),
+            entry("2020-01-02T10:00:00Z", "c", "z", 1000),
+            episode("2020-01-04T10:00:00Z", "s", 1000),
+        ]);
+        assert_eq!(analytics.get_one_play_artists(), vec!["c", "b"]);
+    }
+
+    #[test]
+    fn faded_artists_have_no_recent_plays() {
+        let analytics = analytics(vec![
+            entry("2020-01-01T10:00:00Z", "a", "x", 5000),
+            entry("2020-01-01T11:00:00Z", "b", "y", 9000),
+            entry("2020-01-01T12:00:00Z", "c", "z", 1000),
+            entry("2020-03-01T10:00:00Z", "b", "y", 1000),
+            entry("2020-06-01T10:00:00Z", "d", "w", 1000),
+        ]);
+        assert_eq!(analytics.get_faded_artists(120, 10), vec!["a", "c"]);
+        assert_eq!(analytics.get_faded_artists(120, 1), vec!["a"]);
+        assert_eq!(analytics.get_faded_artists(30, 10), vec!["b", "a", "c"]);
+        assert!(analytics.get_faded_artists(1_000_000_000, 10).is_empty());
+        assert!(analytics.get_faded_artists(i64::MAX, 10).is_empty());
+    }
+
+    #[test]
+    fn unique_albums_are_keyed_by_artist() {
+        let mut a = entry("2020-01-01T10:00:00Z", "a", "x", 1000);
+        a.master_metadata_album_album_name = Some("Greatest Hits".to_owned());
+        let mut b = entry("2020-01-01T11:00:00Z", "b", "y", 1000);
+        b.master_metadata_album_album_name = Some("Greatest Hits".to_owned());
+        let mut c = entry("2020-01-01T12:00:00Z", "a", "z", 1000);
+        c.master_metadata_album_album_name = Some("Greatest Hits".to_owned());
+        let d = entry("2020-01-01T13:00:00Z", "a", "w", 1000);
+        let analytics = analytics(vec![a, b, c, d]);
+        assert_eq!(analytics.count_unique_albums(), 2);
+    }
+
+    #[test]
+    fn scope_counts_basic_export_plays_as_music() {
+        let basic: SpotifyHistoryEntry = crate::SpotifyBasicHistoryEntry {
+            end_time: "2020-01-01T10:00:00Z".parse().unwrap(),
+            artist_name: Some("b".to_owned()),
+            track_name: Some("y".to_owned()),
+            ms_played: 1000,
+        }
+        .into();
+        let analytics = analytics(vec![
+            entry("2020-01-01T11:00:00Z", "a", "x", 1000),
+            basic,
+            episode("2020-01-01T12:00:00Z", "s", 1000),
+        ]);
+        let artists = |scope| {
+            analytics
+                .scoped(scope)
+                .get_all_top_artists()
+                .into_iter()
+                .map(|(a, _)| a.to_owned())
+                .collect::<Vec<_>>()
+        };
+        let mut music = artists(Scope::Music);
+        music.sort();
+        assert_eq!(music, vec!["a", "b"]);
+        assert!(artists(Scope::Podcasts).is_empty());
+        assert_eq!(analytics.scoped(Scope::Podcasts).total_plays(), 1);
+    }
+
+    #[test]
+    fn unique_shows_skip_music() {
+        let analytics = analytics(vec![
+            episode("2020-01-01T10:00:00Z", "s", 1000),
+            episode("2020-01-01T11:00:00Z", "s", 1000),
+            episode("2020-01-01T12:00:00Z", "t", 1000),
+            entry("2020-01-01T13:00:00Z", "a", "x", 1000),
+        ]);
+        assert_eq!(analytics.count_unique_shows(), 2);
+    }
+
+    #[test]
+    fn offline_split_treats_none_as_online() {
+        let with_offline = |offline, ms_played| SpotifyHistoryEntry {
+            offline,
+            ..entry("2020-01-01T10:00:00Z", "a", "x", ms_played)
+        };
+        let analytics = analytics(vec![
+            with_offline(Some(true), 1000),
+            with_offline(Some(false), 2000),
+            with_offline(None, 4000),
+            with_offline(Some(true), 8000),
+        ]);
+        assert_eq!(analytics.get_offline_split(), (9000, 6000));
+    }
+
+    #[test]
+    fn overall_skip_ratio_ignores_unflagged_plays() {
+        let with_skipped = |skipped| SpotifyHistoryEntry {
+            skipped,
+            ..entry("2020-01-01T10:00:00Z", "a", "x", 1000)
+        };
+        assert_eq!(
+            analytics(vec![with_skipped(None)]).get_overall_skip_ratio(),
+            (0, 0, 0.0)
+        );
+        let analytics = analytics(vec![
+            with_skipped(Some(true)),
+            with_skipped(Some(false)),
+            with_skipped(Some(false)),
+            with_skipped(Some(false)),
+            with_skipped(None),
+            with_skipped(None),
+        ]);
+        assert_eq!(analytics.get_overall_skip_ratio(), (1, 4, 0.25));
+    }
+
+    #[test]
+    fn incognito_stats_count_only_incognito_plays() {
+        let with_incognito = |incognito_mode, ms_played| SpotifyHistoryEntry {
+            incognito_mode,
+            ..entry("2020-01-01T10:00:00Z", "a", "x", ms_played)
+        };
+        let analytics = analytics(vec![
+            with_incognito(Some(true), 1000),
+            with_incognito(Some(false), 2000),
+            with_incognito(None, 4000),
+            with_incognito(Some(true), 8000),
+        ]);
+        assert_eq!(analytics.get_incognito_stats(), (2, 9000));
+        assert_eq!(
+            analytics
+                .filtered(DateTime::<Utc>::MIN_UTC, DateTime::<Utc>::MIN_UTC)
+                .get_incognito_stats(),
+            (0, 0)
+        );
+    }
+
+    #[test]
+    fn read_only_matches_loaded_history() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("history.db");
+        let file = dir.path().join("history.json");
+        write_json(
+            &file,
+            &[
+                entry("2020-01-01T10:00:00Z", "a", "x", 1000),
+                entry("2020-01-03T10:00:00Z", "b", "y", 2500),
+            ],
+        );
+        let mut analytics = SpotifyAnalytics::new(&db_path).unwrap();
+        analytics
+            .deserialize_extended_streaming_history_json(&file)
+            .unwrap();
+        analytics.save().unwrap();
+
+        let loaded = SpotifyAnalytics::new(&db_path).unwrap();
+        let read_only = SpotifyAnalytics::read_only(
+            &db_path,
+            StdDuration::from_secs(DEFAULT_BUSY_TIMEOUT_SECS),
+        )
+        .unwrap();
+        assert_eq!(
+            read_only.total_plays().unwrap(),
+            loaded.total_plays() as u64
+        );
+        assert_eq!(
+            read_only.total_ms_played().unwrap(),
+            loaded.total_ms_played()
+        );
+        assert_eq!(read_only.date_range().unwrap(), loaded.date_range());
+        assert_eq!(
+            read_only.get_top_n_artists(1).unwrap(),
+            vec![("b".to_owned(), 2500)]
+        );
+    }
+
+    #[test]
+    fn compare_ranges_reports_change_from_a_to_b() {
+        let analytics = analytics(vec![
+            entry("2020-06-01T10:00:00Z", "a", "x", 1000),
+            entry("2021-06-01T10:00:00Z", "b", "y", 3000),
+            entry("2021-06-02T10:00:00Z", "c", "z", 500),
+        ]);
+        let year = |y: i32| {
+            (
+                Utc.with_ymd_and_hms(y, 1, 1, 0, 0, 0).unwrap(),
+                Utc.with_ymd_and_hms(y, 12, 31, 23, 59, 59).unwrap(),
+            )
+        };
+        let cmp = analytics.compare_ranges(year(2020), year(2021));
+        assert_eq!(cmp.a.top_artist.as_deref(), Some("a"));
+        assert_eq!(cmp.b.top_artist.as_deref(), Some("b"));
+        assert_eq!(cmp.ms_played_delta, 2500);
+        assert_eq!(cmp.unique_artists_delta, 1);
+        assert_eq!(cmp.unique_tracks_delta, 1);
+    }
+
+    #[test]
+    fn type_errors_name_the_field_and_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("Streaming_History_Audio_2020.json");
+        let mut bad = serde_json::to_value(entry("2020-01-01T10:00:00Z", "a", "x", 1000)).unwrap();
+        bad["ms_played"] = "1000".into();
+        fs::write(&file, serde_json::to_vec(&[bad]).unwrap()).unwrap();
+
+        let err = analytics(vec![]).import_path(&file).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("Streaming_History_Audio_2020.json"));
+        let source = std::error::Error::source(&err).unwrap().to_string();
+        assert!(source.contains("field `ms_played`"), "{source}");
+
+        let mut analytics = analytics(vec![]);
+        analytics.set_skip_invalid(true);
+        analytics.import_path(&file).unwrap();
+        assert!(analytics.incoming.is_empty());
+    }
+
+    #[test]
+    fn skip_invalid_drops_malformed_timestamps() {
+        let mut valid =
+            serde_json::to_value(entry("2020-01-01T10:00:00Z", "a", "x", 1000)).unwrap();
+        let mut invalid = valid.clone();
+        valid["ts"] = "2020-01-01 10:00:00".into();
+        invalid["ts"] = "yesterday".into();
+        let json = serde_json::to_vec(&[invalid, valid]).unwrap();
+
+        let mut analytics = analytics(vec![]);
+        assert!(analytics
+            .deserialize_extended_streaming_history_json_reader(json.as_slice())
+            .is_err());
+
+        analytics.set_skip_invalid(true);
+        analytics
+            .deserialize_extended_streaming_history_json_reader(json.as_slice())
+            .unwrap();
+        let ts: Vec<_> = analytics.incoming.iter().map(|x| x.ts).collect();
+        assert_eq!(
+            ts,
+            vec![Utc.with_ymd_and_hms(2020, 1, 1, 10, 0, 0).unwrap()]
+        );
+    }
+
+    #[test]
+    fn estimated_payout_counts_plays_over_thirty_seconds() {
+        let analytics = analytics(vec![
+            entry("2020-01-01T10:00:00Z", "a", "x", 30_000),
+            entry("2020-01-01T11:00:00Z", "a", "x", 30_001),
+            entry("2020-01-01T12:00:00Z", "b", "y", 60_000),
+            entry("2020-01-01T13:00:00Z", "b", "y", 60_000),
+        ]);
+        assert_eq!(
+            analytics.get_estimated_payout(0.5),
+            vec![("b", 1.0), ("a", 0.5)]
+        );
+    }
+
+    #[test]
+    fn play_events_for_uri_are_chronological() {
+        let analytics = analytics(vec![
+            entry("2020-01-03T10:00:00Z", "a", "x", 1000),
+            entry("2020-01-01T10:00:00Z", "a", "x", 1000),
+            entry("2020-01-02T10:00:00Z", "a", "y", 1000),
+        ]);
+        let day = |d| Utc.with_ymd_and_hms(2020, 1, d, 10, 0, 0).unwrap();
+        assert_eq!(
+            analytics.get_play_events_for_uri("spotify:track:x"),
+            vec![day(1), day(3)]
+        );
+        assert!(analytics.get_play_events_for_uri("x").is_empty());
+    }
+
+    #[test]
+    fn listening_diversity_spans_concentrated_to_even() {
+        assert_eq!(analytics(vec![]).get_listening_diversity(), 0.0);
+        let single = analytics(vec![
+            entry("2020-01-01T10:00:00Z", "a", "x", 1000),
+            entry("2020-01-01T11:00:00Z", "a", "y", 1000),
+        ]);
+        assert_eq!(single.get_listening_diversity(), 0.0);
+        let even = analytics(vec![
+            entry("2020-01-01T10:00:00Z", "a", "x", 1000),
+            entry("2020-01-01T11:00:00Z", "b", "y", 1000),
+            entry("2020-01-01T12:00:00Z", "c", "z", 1000),
+        ]);
+        assert!((even.get_listening_diversity() - 1.0).abs() < 1e-9);
+        let skewed = analytics(vec![
+            entry("2020-01-01T10:00:00Z", "a", "x", 100_000),
+            entry("2020-01-01T11:00:00Z", "b", "y", 1000),
+        ]);
+        assert!(skewed.get_listening_diversity() < 0.1);
+    }
+
+    #[cfg(feature = "polars")]
+    #[test]
+    fn dataframe_has_nullable_typed_columns() {
+        use polars::prelude::*;
+
+        let df = analytics(vec![
+            entry("2020-01-01T10:00:00Z", "a", "x", 1000),
+            episode("2020-01-01T11:00:00Z", "s", 2000),
+        ])
+        .to_dataframe()
+        .unwrap();
+        assert_eq!(df.shape(), (2, 21));
+        assert_eq!(
+            df.column("ts").unwrap().dtype(),
+            &DataType::Datetime(TimeUnit::Milliseconds, Some(TimeZone::UTC))
+        );
+        assert_eq!(df.column("ms_played").unwrap().dtype(), &DataType::UInt64);
+        assert_eq!(df.column("spotify_track_uri").unwrap().null_count(), 1);
+        assert_eq!(df.column("shuffle").unwrap().dtype(), &DataType::Boolean);
+    }
 }
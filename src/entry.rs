@@ -0,0 +1,348 @@
+use crate::error::{AnalyticsError, Result};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use flate2::read::GzDecoder;
+use serde::de::{SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+use tracing::{info_span, warn};
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SpotifyHistoryEntry {
+    #[serde(deserialize_with = "deserialize_ts")]
+    pub ts: DateTime<Utc>,
+    pub username: Option<String>,
+    pub platform: Option<String>,
+    pub ms_played: u64,
+    pub conn_country: Option<String>,
+    pub ip_addr_decrypted: Option<String>,
+    pub user_agent_decrypted: Option<String>,
+    pub master_metadata_track_name: Option<String>,
+    pub master_metadata_album_artist_name: Option<String>,
+    pub master_metadata_album_album_name: Option<String>,
+    pub spotify_track_uri: Option<String>,
+    pub episode_name: Option<String>,
+    pub episode_show_name: Option<String>,
+    pub spotify_episode_uri: Option<String>,
+    pub reason_start: Option<String>,
+    pub reason_end: Option<String>,
+    pub shuffle: Option<bool>,
+    pub skipped: Option<bool>,
+    pub offline: Option<bool>,
+    pub offline_timestamp: Option<u64>,
+    pub incognito_mode: Option<bool>,
+}
+
+/// An entry of the `Streaming_History_Video_*.json` files of an extended
+/// export, which describe video episodes and carry no track metadata.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SpotifyVideoHistoryEntry {
+    #[serde(deserialize_with = "deserialize_ts")]
+    pub ts: DateTime<Utc>,
+    pub username: Option<String>,
+    pub platform: Option<String>,
+    pub ms_played: u64,
+    pub conn_country: Option<String>,
+    pub episode_name: Option<String>,
+    pub episode_show_name: Option<String>,
+    pub spotify_episode_uri: Option<String>,
+    pub reason_start: Option<String>,
+    pub reason_end: Option<String>,
+    pub shuffle: Option<bool>,
+    pub skipped: Option<bool>,
+    pub offline: Option<bool>,
+    pub incognito_mode: Option<bool>,
+}
+
+/// An entry of the basic (non-extended) `StreamingHistory*.json` export.
+#[derive(Debug, Deserialize, Clone)]
+pub struct SpotifyBasicHistoryEntry {
+    #[serde(rename = "endTime", deserialize_with = "deserialize_ts")]
+    pub end_time: DateTime<Utc>,
+    #[serde(rename = "artistName")]
+    pub artist_name: Option<String>,
+    #[serde(rename = "trackName")]
+    pub track_name: Option<String>,
+    #[serde(rename = "msPlayed")]
+    pub ms_played: u64,
+}
+
+/// Zone-less layouts tried after RFC 3339, read as UTC. The last one is what
+/// the basic export writes for `endTime`.
+const NAIVE_TS_FORMATS: [&str; 3] = [
+    "%Y-%m-%dT%H:%M:%S%.f",
+    "%Y-%m-%d %H:%M:%S%.f",
+    "%Y-%m-%d %H:%M",
+];
+
+/// Parses the timestamp layouts Spotify exports have used: RFC 3339 with or
+/// without fractional seconds, `Z` or a numeric offset, either `T` or a
+/// space between date and time, and the zone-less [`NAIVE_TS_FORMATS`].
+pub fn parse_ts(s: &str) -> Option<DateTime<Utc>> {
+    let s = s.trim();
+    if let Ok(ts) = DateTime::parse_from_rfc3339(s) {
+        return Some(ts.with_timezone(&Utc));
+    }
+    NAIVE_TS_FORMATS
+        .iter()
+        .find_map(|f| NaiveDateTime::parse_from_str(s, f).ok())
+        .map(|x| x.and_utc())
+}
+
+fn deserialize_ts<'de, D>(deserializer: D) -> Result<DateTime<Utc>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    parse_ts(&s).ok_or_else(|| serde::de::Error::custom(format!("invalid timestamp {s:?}")))
+}
+
+impl From<SpotifyBasicHistoryEntry> for SpotifyHistoryEntry {
+    fn from(e: SpotifyBasicHistoryEntry) -> Self {
+        Self {
+            ts: e.end_time,
+            username: None,
+            platform: None,
+            ms_played: e.ms_played,
+            conn_country: None,
+            ip_addr_decrypted: None,
+            user_agent_decrypted: None,
+            master_metadata_track_name: e.track_name,
+            master_metadata_album_artist_name: e.artist_name,
+            master_metadata_album_album_name: None,
+            spotify_track_uri: None,
+            episode_name: None,
+            episode_show_name: None,
+            spotify_episode_uri: None,
+            reason_start: None,
+            reason_end: None,
+            shuffle: None,
+            skipped: None,
+            offline: None,
+            offline_timestamp: None,
+            incognito_mode: None,
+        }
+    }
+}
+
+/// Either export schema. Records are converted as soon as they are parsed,
+/// so the size difference is moot.
+#[allow(clippy::large_enum_variant)]
+enum HistoryRecord {
+    Extended(SpotifyHistoryEntry),
+    Basic(SpotifyBasicHistoryEntry),
+}
+
+impl<'de> Deserialize<'de> for HistoryRecord {
+    /// Picks the schema by the basic export's `endTime` key rather than
+    /// trying both, so a malformed entry is reported against the schema it
+    /// was meant to follow, naming the offending field.
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let v = serde_json::Value::deserialize(deserializer)?;
+        let r = if v.get("endTime").is_some() {
+            serde_path_to_error::deserialize(v).map(HistoryRecord::Basic)
+        } else {
+            serde_path_to_error::deserialize(v).map(HistoryRecord::Extended)
+        };
+        r.map_err(|err| {
+            let field = err.path().to_string();
+            let err = err.into_inner();
+            if field == "." {
+                serde::de::Error::custom(err)
+            } else {
+                serde::de::Error::custom(format!("field `{field}`: {err}"))
+            }
+        })
+    }
+}
+
+impl From<HistoryRecord> for SpotifyHistoryEntry {
+    fn from(r: HistoryRecord) -> Self {
+        match r {
+            HistoryRecord::Extended(e) => e,
+            HistoryRecord::Basic(e) => e.into(),
+        }
+    }
+}
+
+struct HistoryVisitor<'a> {
+    out: &'a mut Vec<SpotifyHistoryEntry>,
+    skip_invalid: bool,
+}
+
+impl<'de, 'a> Visitor<'de> for HistoryVisitor<'a> {
+    /// Number of entries skipped as invalid.
+    type Value = usize;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("an array of streaming history entries")
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<usize, A::Error>
+    where
+        A: SeqAccess<'de>,
+    {
+        if !self.skip_invalid {
+            while let Some(e) = seq.next_element::<HistoryRecord>()? {
+                self.out.push(e.into());
+            }
+            return Ok(0);
+        }
+
+        let mut skipped = 0;
+        while let Some(v) = seq.next_element::<serde_json::Value>()? {
+            match HistoryRecord::deserialize(v) {
+                Ok(e) => self.out.push(e.into()),
+                Err(err) => {
+                    warn!(%err, "skipping invalid entry");
+                    skipped += 1;
+                }
+            }
+        }
+        Ok(skipped)
+    }
+}
+
+/// Streams history entries from `reader` into `out`. The input is either a
+/// JSON array or, when the first non-whitespace byte isn't `[`, JSON Lines
+/// with one entry per line. With `skip_invalid`, entries that fail to
+/// deserialize are logged and dropped instead of failing the whole input.
+/// Returns how many entries were dropped.
+pub(crate) fn read_history<R: Read>(
+    reader: R,
+    out: &mut Vec<SpotifyHistoryEntry>,
+    skip_invalid: bool,
+) -> Result<usize> {
+    let mut reader = BufReader::new(reader);
+    let skipped = if starts_with_array(&mut reader)? {
+        let mut de = serde_json::Deserializer::from_reader(reader);
+        let skipped = de.deserialize_seq(HistoryVisitor { out, skip_invalid })?;
+        de.end()?;
+        skipped
+    } else {
+        read_history_lines(reader, out, skip_invalid)?
+    };
+    if skipped > 0 {
+        warn!(skipped, "skipped invalid entries");
+    }
+    Ok(skipped)
+}
+
+/// Skips leading whitespace and reports whether the next byte is `[`.
+fn starts_with_array<R: BufRead>(reader: &mut R) -> Result<bool> {
+    loop {
+        let buf = reader.fill_buf()?;
+        let Some(&b) = buf.first() else {
+            return Ok(true);
+        };
+        if !b.is_ascii_whitespace() {
+            return Ok(b == b'[');
+        }
+        reader.consume(1);
+    }
+}
+
+fn read_history_lines<R: Read>(
+    reader: R,
+    out: &mut Vec<SpotifyHistoryEntry>,
+    skip_invalid: bool,
+) -> Result<usize> {
+    let de = serde_json::Deserializer::from_reader(reader);
+    if !skip_invalid {
+        for e in de.into_iter::<HistoryRecord>() {
+            out.push(e?.into());
+        }
+        return Ok(0);
+    }
+
+    let mut skipped = 0;
+    for v in de.into_iter::<serde_json::Value>() {
+        match HistoryRecord::deserialize(v?) {
+            Ok(e) => out.push(e.into()),
+            Err(err) => {
+                warn!(%err, "skipping invalid entry");
+                skipped += 1;
+            }
+        }
+    }
+    Ok(skipped)
+}
+
+/// Wraps `reader` in a gzip decoder when `name` ends in `.gz`.
+pub(crate) fn decompressed<'a, R: Read + 'a>(name: &str, reader: R) -> Box<dyn Read + 'a> {
+    if name.ends_with(".gz") {
+        Box::new(GzDecoder::new(reader))
+    } else {
+        Box::new(reader)
+    }
+}
+
+/// Opens `path` for reading, decompressing `.json.gz` files on the fly.
+pub(crate) fn open_history_file(path: &Path) -> Result<Box<dyn Read>> {
+    let file = File::open(path).map_err(|source| AnalyticsError::Open {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    Ok(decompressed(&path.to_string_lossy(), BufReader::new(file)))
+}
+
+/// Reads the history file at `path`, returning its entries and how many
+/// invalid ones were dropped.
+pub(crate) fn read_history_file(
+    path: &Path,
+    skip_invalid: bool,
+) -> Result<(Vec<SpotifyHistoryEntry>, usize)> {
+    // Entries dropped with `skip_invalid` are logged under the file's name.
+    let _span = info_span!("read_history_file", path = %path.display()).entered();
+    let file = open_history_file(path)?;
+    let mut history = Vec::new();
+    let skipped =
+        read_history(file, &mut history, skip_invalid).map_err(|source| AnalyticsError::Parse {
+            name: path.display().to_string(),
+            source: Box::new(source),
+        })?;
+    Ok((history, skipped))
+}
+
+pub(crate) fn read_video_history<R: Read>(reader: R) -> Result<Vec<SpotifyVideoHistoryEntry>> {
+    Ok(serde_json::from_reader(BufReader::new(reader))?)
+}
+
+pub(crate) fn read_video_history_file(path: &Path) -> Result<Vec<SpotifyVideoHistoryEntry>> {
+    let file = open_history_file(path)?;
+    read_video_history(file).map_err(|source| AnalyticsError::Parse {
+        name: path.display().to_string(),
+        source: Box::new(source),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timestamps_parse_in_every_export_layout() {
+        for ts in [
+            "2020-01-01T10:00:00Z",
+            "2020-01-01T10:00:00.000Z",
+            "2020-01-01T10:00:00.123456Z",
+            "2020-01-01T11:00:00+01:00",
+            "2020-01-01 10:00:00Z",
+            "2020-01-01T10:00:00",
+            "2020-01-01 10:00:00",
+            "2020-01-01 10:00",
+        ] {
+            assert_eq!(
+                parse_ts(ts).map(|x| x.timestamp()),
+                Some(1_577_872_800),
+                "{ts}"
+            );
+        }
+        assert_eq!(parse_ts("01/01/2020 10:00"), None);
+    }
+}
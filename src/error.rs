@@ -0,0 +1,74 @@
+use chrono::{DateTime, Utc};
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+use thiserror::Error;
+
+/// Errors returned by the library.
+#[derive(Debug, Error)]
+pub enum AnalyticsError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    Db(#[from] rusqlite::Error),
+    #[error(transparent)]
+    Migration(#[from] rusqlite_migration::Error),
+    #[error(transparent)]
+    Row(#[from] serde_rusqlite::Error),
+    #[error(transparent)]
+    Zip(#[from] zip::result::ZipError),
+    #[error(transparent)]
+    Csv(#[from] csv::Error),
+    #[cfg(feature = "spotify-api")]
+    #[error(transparent)]
+    SpotifyApi(#[from] rspotify::ClientError),
+    #[cfg(feature = "polars")]
+    #[error(transparent)]
+    Polars(#[from] polars::error::PolarsError),
+    #[error("failed to open {}", path.display())]
+    Open {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    /// A history file or archive entry could not be parsed.
+    #[error("failed to parse {name}")]
+    Parse {
+        name: String,
+        #[source]
+        source: Box<AnalyticsError>,
+    },
+    #[error("failed to insert row {row}: {entry}")]
+    Insert {
+        row: usize,
+        entry: String,
+        #[source]
+        source: rusqlite::Error,
+    },
+    /// A batch failed after the batches holding the first `committed`
+    /// incoming rows were saved. Rows that were already in the database count
+    /// as committed too.
+    #[error("save stopped with {committed} rows already committed")]
+    Save {
+        committed: usize,
+        #[source]
+        source: Box<AnalyticsError>,
+    },
+    /// Another process held the database for longer than the busy timeout,
+    /// while opening it or in a later statement.
+    #[error("{} is locked by another process, gave up after {timeout:?}", path.display())]
+    Locked { path: PathBuf, timeout: Duration },
+    #[error("only read-only statements are allowed")]
+    NotReadOnly,
+    /// An entry's `ms_played` failed validation during a strict import.
+    #[error("{anomaly} at {ts} (ms_played = {ms_played})")]
+    ImplausiblePlay {
+        anomaly: &'static str,
+        ts: DateTime<Utc>,
+        ms_played: u64,
+    },
+}
+
+pub type Result<T, E = AnalyticsError> = std::result::Result<T, E>;
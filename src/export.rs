@@ -0,0 +1,174 @@
+use crate::entry::SpotifyHistoryEntry;
+use crate::error::Result;
+use chrono::Duration;
+use serde::Serialize;
+use std::io::Write;
+
+/// last.fm only counts a play as a scrobble after 30 seconds.
+pub const DEFAULT_SCROBBLE_MIN_MS: u64 = 30_000;
+
+/// Writes `history` as CSV with a header row named after the
+/// [`SpotifyHistoryEntry`] fields. `None` values become empty cells and
+/// timestamps are written as RFC3339.
+pub fn write_csv<W: Write>(
+    history: &[SpotifyHistoryEntry],
+    writer: W,
+    delimiter: u8,
+) -> Result<()> {
+    let mut w = csv::WriterBuilder::new()
+        .delimiter(delimiter)
+        .from_writer(writer);
+    for e in history {
+        w.serialize(e)?;
+    }
+    w.flush()?;
+    Ok(())
+}
+
+/// Writes `history` as Parquet, with the column types of
+/// [`SpotifyAnalytics::to_dataframe`](crate::SpotifyAnalytics::to_dataframe).
+#[cfg(feature = "parquet")]
+pub fn write_parquet<W: Write>(history: &[SpotifyHistoryEntry], writer: W) -> Result<()> {
+    let mut df = crate::dataframe::history_to_dataframe(history)?;
+    polars::prelude::ParquetWriter::new(writer).finish(&mut df)?;
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct Scrobble<'a> {
+    artist: &'a str,
+    track: &'a str,
+    album: &'a str,
+    timestamp: i64,
+}
+
+/// Writes music plays of at least `min_ms` as last.fm-style scrobbles. The
+/// timestamp is the unix time the play started, since the export records
+/// when it ended. Plays whose start would fall outside the representable
+/// range are skipped.
+pub fn write_scrobbles<W: Write>(
+    history: &[SpotifyHistoryEntry],
+    writer: W,
+    delimiter: u8,
+    min_ms: u64,
+) -> Result<()> {
+    let mut w = csv::WriterBuilder::new()
+        .delimiter(delimiter)
+        .from_writer(writer);
+    for e in history.iter().filter(|x| x.ms_played >= min_ms) {
+        let (Some(track), Some(artist)) = (
+            e.master_metadata_track_name.as_deref(),
+            e.master_metadata_album_artist_name.as_deref(),
+        ) else {
+            continue;
+        };
+        let Some(started) = i64::try_from(e.ms_played)
+            .ok()
+            .and_then(|ms| e.ts.checked_sub_signed(Duration::milliseconds(ms)))
+        else {
+            continue;
+        };
+        w.serialize(Scrobble {
+            artist,
+            track,
+            album: e.master_metadata_album_album_name.as_deref().unwrap_or(""),
+            timestamp: started.timestamp(),
+        })?;
+    }
+    w.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn play(ts: &str, track: &str, ms_played: u64) -> SpotifyHistoryEntry {
+        SpotifyHistoryEntry {
+            ts: ts.parse().unwrap(),
+            username: None,
+            platform: None,
+            ms_played,
+            conn_country: None,
+            ip_addr_decrypted: None,
+            user_agent_decrypted: None,
+            master_metadata_track_name: Some(track.to_owned()),
+            master_metadata_album_artist_name: Some("artist".to_owned()),
+            master_metadata_album_album_name: None,
+            spotify_track_uri: None,
+            episode_name: None,
+            episode_show_name: None,
+            spotify_episode_uri: None,
+            reason_start: None,
+            reason_end: None,
+            shuffle: None,
+            skipped: None,
+            offline: None,
+            offline_timestamp: None,
+            incognito_mode: None,
+        }
+    }
+
+    #[test]
+    fn scrobbles_apply_threshold_and_use_start_time() {
+        let history = vec![
+            play("2020-01-01T10:01:00Z", "long", 60_000),
+            play("2020-01-01T10:02:00Z", "short", 29_999),
+            play("2020-01-01T10:03:00Z", "huge", u64::MAX),
+        ];
+        let mut buf = Vec::new();
+        write_scrobbles(&history, &mut buf, b',', DEFAULT_SCROBBLE_MIN_MS).unwrap();
+
+        let started = "2020-01-01T10:00:00Z"
+            .parse::<chrono::DateTime<chrono::Utc>>()
+            .unwrap()
+            .timestamp();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            format!("artist,track,album,timestamp\nartist,long,,{started}\n")
+        );
+    }
+
+    #[test]
+    fn csv_writes_header_and_empty_cells() {
+        let mut buf = Vec::new();
+        write_csv(&[play("2020-01-01T10:00:00Z", "x", 1000)], &mut buf, b';').unwrap();
+        let text = String::from_utf8(buf).unwrap();
+        let mut lines = text.lines();
+        assert!(lines
+            .next()
+            .unwrap()
+            .starts_with("ts;username;platform;ms_played;"));
+        assert!(lines
+            .next()
+            .unwrap()
+            .starts_with("2020-01-01T10:00:00Z;;;1000;"));
+    }
+
+    #[cfg(feature = "parquet")]
+    #[test]
+    fn parquet_export_round_trips() {
+        use polars::prelude::*;
+
+        let history = vec![
+            play("2020-01-01T10:00:00Z", "x", 1000),
+            SpotifyHistoryEntry {
+                master_metadata_track_name: None,
+                master_metadata_album_artist_name: None,
+                episode_name: Some("episode".to_owned()),
+                episode_show_name: Some("show".to_owned()),
+                spotify_episode_uri: Some("spotify:episode:e".to_owned()),
+                ..play("2020-01-01T11:00:00Z", "", 2000)
+            },
+        ];
+        let mut buf = Vec::new();
+        write_parquet(&history, &mut buf).unwrap();
+        let df = ParquetReader::new(std::io::Cursor::new(buf))
+            .finish()
+            .unwrap();
+        assert_eq!(
+            df,
+            crate::dataframe::history_to_dataframe(&history).unwrap()
+        );
+    }
+}
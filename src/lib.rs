@@ -0,0 +1,19 @@
+//! Imports Spotify streaming history exports into SQLite and analyses them.
+//!
+//! [`SpotifyAnalytics`] loads the history stored in a database, imports new
+//! export files and exposes the aggregations (top artists, listening by
+//! year, ...) used by the `spotify-analytics` binary.
+
+#[cfg(feature = "polars")]
+pub mod dataframe;
+pub mod db;
+pub mod entry;
+pub mod error;
+pub mod export;
+pub mod report;
+#[cfg(feature = "spotify-api")]
+pub mod spotify_api;
+
+pub use db::{get_db, get_db_with_busy_timeout, SpotifyAnalytics};
+pub use entry::{SpotifyBasicHistoryEntry, SpotifyHistoryEntry, SpotifyVideoHistoryEntry};
+pub use error::{AnalyticsError, Result};
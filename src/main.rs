@@ -1,21 +1,356 @@
-mod db;
-
-use clap::Parser;
-use color_eyre::eyre::Result;
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use chrono_tz::Tz;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use color_eyre::eyre::{bail, eyre, Result};
+use output::{
+    emit, ArtistReport, Heatmap, OutputFormat, Ranking, Series, Table, WrappedReport, WEEKDAYS,
+};
+use spotify_analytics::db::Scope;
+use spotify_analytics::report::StatsReport;
+use spotify_analytics::{db, export, SpotifyAnalytics};
 use std::fmt::Debug;
-use std::path::PathBuf;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration as StdDuration;
+use tracing::info;
 use tracing_subscriber::{prelude::__tracing_subscriber_SubscriberExt, util::SubscriberInitExt};
 
+mod output;
+
 #[derive(Debug, Parser)]
 #[command(author, version, about, long_about = None)]
+struct Cli {
+    #[arg(long, global = true, env = "SPOTIFY_ANALYTICS_DB", default_value = DEFAULT_DB_PATH)]
+    db: PathBuf,
+    #[arg(long, global = true)]
+    username: Option<String>,
+    #[arg(long, global = true, value_enum, default_value_t = OutputFormat::Table)]
+    output: OutputFormat,
+    #[arg(long, global = true, default_value_t = db::DEFAULT_BUSY_TIMEOUT_SECS)]
+    busy_timeout: u64,
+    #[command(subcommand)]
+    command: Commands,
+}
+
+#[derive(Debug, Subcommand)]
 enum Commands {
     Parse(ParseCommand),
+    Stats(StatsCommand),
+    Export(ExportCommand),
+    Top(TopCommand),
+    ByYear(ByYearCommand),
+    ByMonth(ByMonthCommand),
+    ByWeekday(ByWeekdayCommand),
+    Heatmap(HeatmapCommand),
+    Wrapped(WrappedCommand),
+    When(WhenCommand),
+    Query(QueryCommand),
+    ByCountry(ByCountryCommand),
+    Dedup(DedupCommand),
+    Reset(ResetCommand),
+    Prune(PruneCommand),
+    Artist(ArtistCommand),
+    Search(SearchCommand),
+    Usernames(UsernamesCommand),
+    Discoveries(DiscoveriesCommand),
+    Compare(CompareCommand),
+    Payout(PayoutCommand),
+    Plays(PlaysCommand),
+    OneHits(OneHitsCommand),
+    Annotate(AnnotateCommand),
+    ByAnnotation(ByAnnotationCommand),
+    Faded(FadedCommand),
+    #[cfg(feature = "spotify-api")]
+    FetchDurations(FetchDurationsCommand),
 }
 
 #[derive(Debug, Parser)]
 struct ParseCommand {
+    #[arg(short, long, num_args = 1.., required_unless_present = "stdin", conflicts_with = "stdin")]
+    path: Vec<PathBuf>,
+    #[arg(long)]
+    stdin: bool,
+    #[arg(short, long)]
+    quiet: bool,
+    #[arg(long)]
+    dry_run: bool,
+    #[arg(long)]
+    skip_invalid: bool,
+    #[arg(long)]
+    strict: bool,
+    #[arg(long, default_value_t = db::DEFAULT_BATCH_SIZE)]
+    batch_size: usize,
+    #[arg(short, long)]
+    force: bool,
+    #[arg(long)]
+    since: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Args)]
+struct RangeArgs {
+    #[arg(long)]
+    from: Option<DateTime<Utc>>,
+    #[arg(long)]
+    to: Option<DateTime<Utc>>,
+}
+
+impl RangeArgs {
+    fn is_unbounded(&self) -> bool {
+        self.from.is_none() && self.to.is_none()
+    }
+
+    fn contains(&self, ts: DateTime<Utc>) -> bool {
+        self.from.is_none_or(|from| ts >= from) && self.to.is_none_or(|to| ts <= to)
+    }
+
+    fn apply(&self, spotify_analytics: SpotifyAnalytics) -> SpotifyAnalytics {
+        if self.is_unbounded() {
+            return spotify_analytics;
+        }
+        spotify_analytics.filtered(
+            self.from.unwrap_or(DateTime::<Utc>::MIN_UTC),
+            self.to.unwrap_or(DateTime::<Utc>::MAX_UTC),
+        )
+    }
+}
+
+#[derive(Debug, Args)]
+struct ScopeArgs {
+    #[arg(long, conflicts_with = "podcasts_only")]
+    music_only: bool,
+    #[arg(long)]
+    podcasts_only: bool,
+}
+
+impl ScopeArgs {
+    fn scope(&self) -> Option<Scope> {
+        if self.music_only {
+            Some(Scope::Music)
+        } else if self.podcasts_only {
+            Some(Scope::Podcasts)
+        } else {
+            None
+        }
+    }
+
+    fn apply(&self, spotify_analytics: SpotifyAnalytics) -> SpotifyAnalytics {
+        match self.scope() {
+            Some(scope) => spotify_analytics.scoped(scope),
+            None => spotify_analytics,
+        }
+    }
+}
+
+#[derive(Debug, Parser)]
+struct StatsCommand {
+    #[command(flatten)]
+    range: RangeArgs,
+    #[command(flatten)]
+    scope: ScopeArgs,
+    #[arg(long, default_value_t = Tz::UTC)]
+    tz: Tz,
+}
+
+#[derive(Debug, Parser)]
+struct SearchCommand {
+    query: String,
+}
+
+#[derive(Debug, Parser)]
+struct ExportCommand {
     #[arg(short, long)]
-    path: PathBuf,
+    out: PathBuf,
+    #[arg(short, long, value_enum, default_value_t = ExportFormat::Csv)]
+    format: ExportFormat,
+    #[arg(long)]
+    tsv: bool,
+    #[arg(long, default_value_t = export::DEFAULT_SCROBBLE_MIN_MS)]
+    min_ms: u64,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum ExportFormat {
+    Csv,
+    Scrobbles,
+    #[cfg(feature = "parquet")]
+    Parquet,
+}
+
+#[derive(Debug, Parser)]
+struct TopCommand {
+    #[arg(short, long, value_enum, default_value_t = TopKind::Artist)]
+    kind: TopKind,
+    #[arg(short, long, default_value_t = 10)]
+    limit: usize,
+    #[arg(long, value_enum, default_value_t = TopBy::Time)]
+    by: TopBy,
+    #[command(flatten)]
+    range: RangeArgs,
+    #[command(flatten)]
+    scope: ScopeArgs,
+}
+
+#[derive(Debug, Parser)]
+struct ByYearCommand {}
+
+#[derive(Debug, Parser)]
+struct ByMonthCommand {}
+
+#[derive(Debug, Parser)]
+struct ByWeekdayCommand {
+    #[arg(long, default_value_t = Tz::UTC)]
+    tz: Tz,
+}
+
+#[derive(Debug, Parser)]
+struct HeatmapCommand {
+    #[arg(long, default_value_t = Tz::UTC)]
+    tz: Tz,
+}
+
+#[derive(Debug, Parser)]
+struct UsernamesCommand {}
+
+#[derive(Debug, Parser)]
+struct DiscoveriesCommand {
+    #[command(flatten)]
+    range: RangeArgs,
+}
+
+#[derive(Debug, Parser)]
+struct CompareCommand {
+    #[arg(long, num_args = 2, value_names = ["FROM", "TO"], required = true)]
+    range_a: Vec<DateTime<Utc>>,
+    #[arg(long, num_args = 2, value_names = ["FROM", "TO"], required = true)]
+    range_b: Vec<DateTime<Utc>>,
+}
+
+#[derive(Debug, Parser)]
+struct PayoutCommand {
+    #[arg(long, default_value_t = db::DEFAULT_PAYOUT_PER_STREAM_USD)]
+    rate: f64,
+    #[arg(short, long, default_value_t = 10)]
+    limit: usize,
+}
+
+#[derive(Debug, Parser)]
+struct PlaysCommand {
+    #[arg(short, long)]
+    uri: String,
+}
+
+#[derive(Debug, Parser)]
+struct OneHitsCommand {}
+
+#[derive(Debug, Parser)]
+struct AnnotateCommand {
+    label: String,
+    #[arg(long)]
+    from: DateTime<Utc>,
+    #[arg(long)]
+    to: DateTime<Utc>,
+}
+
+#[derive(Debug, Parser)]
+struct ByAnnotationCommand {}
+
+#[derive(Debug, Parser)]
+struct FadedCommand {
+    #[arg(long, default_value_t = 180, value_parser = clap::value_parser!(i64).range(0..=36_500))]
+    days: i64,
+    #[arg(short, long, default_value_t = 10)]
+    limit: usize,
+}
+
+#[cfg(feature = "spotify-api")]
+#[derive(Debug, Parser)]
+struct FetchDurationsCommand {
+    #[arg(long, env = "RSPOTIFY_CLIENT_ID")]
+    client_id: String,
+    #[arg(long, env = "RSPOTIFY_CLIENT_SECRET", hide_env_values = true)]
+    client_secret: String,
+}
+
+#[derive(Debug, Parser)]
+struct ByCountryCommand {}
+
+#[derive(Debug, Parser)]
+struct WrappedCommand {
+    #[arg(short, long)]
+    year: i32,
+    #[arg(long, default_value_t = Tz::UTC)]
+    tz: Tz,
+}
+
+#[derive(Debug, Parser)]
+struct WhenCommand {
+    #[arg(short, long)]
+    artist: String,
+}
+
+#[derive(Debug, Parser)]
+struct QueryCommand {
+    #[arg(short, long)]
+    sql: String,
+}
+
+#[derive(Debug, Parser)]
+struct ResetCommand {
+    #[arg(short, long)]
+    yes: bool,
+}
+
+#[derive(Debug, Parser)]
+struct PruneCommand {
+    #[command(flatten)]
+    range: RangeArgs,
+    #[arg(long)]
+    yes: bool,
+}
+
+#[derive(Debug, Parser)]
+struct DedupCommand {
+    #[arg(long)]
+    apply: bool,
+}
+
+#[derive(Debug, Parser)]
+struct ArtistCommand {
+    #[arg(short, long)]
+    name: String,
+    #[arg(long)]
+    trend: bool,
+    #[arg(long, num_args = 0..=1, default_missing_value = "10")]
+    top_tracks: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum TopKind {
+    Artist,
+    Track,
+    Album,
+    Show,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum TopBy {
+    Time,
+    Count,
+}
+
+const DEFAULT_DB_PATH: &str = "./spotify_history.db";
+
+fn load(
+    db: &Path,
+    busy_timeout: StdDuration,
+    username: &Option<String>,
+) -> Result<SpotifyAnalytics> {
+    let mut spotify_analytics = SpotifyAnalytics::with_busy_timeout(db, busy_timeout)?;
+    if let Some(username) = username {
+        spotify_analytics.set_username(username.clone());
+    }
+    Ok(spotify_analytics)
 }
 
 fn main() -> Result<()> {
@@ -23,21 +358,468 @@ fn main() -> Result<()> {
         .with(
             tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| "info".into()),
         )
-        .with(tracing_subscriber::fmt::layer())
+        // Logs go to stderr so they never mix with json or csv output.
+        .with(tracing_subscriber::fmt::layer().with_writer(io::stderr))
         .init();
 
     color_eyre::install()?;
 
-    let cmd = Commands::parse();
-    match cmd {
-        Commands::Parse(ParseCommand { path }) => {
-            // polar::fun_name(path)?;
-            let mut spotify_analytics = db::SpotifyAnalytics::new()?;
-            let top_artists = spotify_analytics.get_top_10_artists();
-            dbg!(top_artists);
-            spotify_analytics
-                .deserialize_extended_streaming_history_json_files_from_folder(path)?;
-            spotify_analytics.save()?;
+    let Cli {
+        db,
+        username,
+        output,
+        busy_timeout,
+        command,
+    } = Cli::parse();
+    let busy_timeout = StdDuration::from_secs(busy_timeout);
+    match command {
+        Commands::Parse(ParseCommand {
+            path,
+            stdin,
+            quiet,
+            dry_run,
+            skip_invalid,
+            strict,
+            batch_size,
+            force,
+            since,
+        }) => {
+            let mut spotify_analytics = load(&db, busy_timeout, &username)?;
+            spotify_analytics.set_show_progress(!quiet);
+            spotify_analytics.set_skip_invalid(skip_invalid);
+            spotify_analytics.set_strict(strict);
+            spotify_analytics.set_batch_size(batch_size);
+            spotify_analytics.set_force(force);
+            emit(
+                &spotify_analytics
+                    .get_top_10_artists()
+                    .into_iter()
+                    .collect::<Ranking>(),
+                output,
+            )?;
+            if stdin {
+                spotify_analytics.import_reader(io::stdin().lock())?;
+            }
+            for path in path {
+                spotify_analytics.import_path(path)?;
+            }
+            let duplicates = spotify_analytics.dedup_incoming();
+            if duplicates > 0 {
+                info!(duplicates, "dropped plays repeated across import paths");
+            }
+            if let Some(since) = since {
+                let filtered = spotify_analytics.drop_incoming_before(since);
+                info!(filtered, %since, "dropped plays older than --since");
+            }
+            if !spotify_analytics.incoming_video().is_empty() {
+                info!(
+                    videos = spotify_analytics.incoming_video().len(),
+                    "parsed video plays"
+                );
+            }
+            if dry_run {
+                eprintln!(
+                    "would insert {} of {} parsed entries",
+                    spotify_analytics.count_new_entries()?,
+                    spotify_analytics.incoming().len()
+                );
+                if let Some((min_ts, max_ts)) = spotify_analytics.incoming_date_range() {
+                    eprintln!("incoming range: {} to {}", min_ts, max_ts);
+                }
+                return Ok(());
+            }
+            let report = spotify_analytics.save()?;
+            eprintln!(
+                "imported {} new plays, skipped {} duplicates",
+                report.inserted, report.skipped_duplicates
+            );
+            if report.errors > 0 {
+                eprintln!("dropped {} invalid entries", report.errors);
+            }
+        }
+        Commands::Stats(StatsCommand { range, scope, tz }) => {
+            let spotify_analytics = scope.apply(range.apply(load(&db, busy_timeout, &username)?));
+            emit(&StatsReport::new(&spotify_analytics, 10, &tz), output)?;
+        }
+        Commands::Export(ExportCommand {
+            out,
+            format,
+            tsv,
+            min_ms,
+        }) => {
+            let spotify_analytics = load(&db, busy_timeout, &username)?;
+            let file = BufWriter::new(File::create(out)?);
+            let delimiter = if tsv { b'\t' } else { b',' };
+            match format {
+                ExportFormat::Csv => {
+                    export::write_csv(spotify_analytics.history(), file, delimiter)?
+                }
+                ExportFormat::Scrobbles => {
+                    export::write_scrobbles(spotify_analytics.history(), file, delimiter, min_ms)?
+                }
+                #[cfg(feature = "parquet")]
+                ExportFormat::Parquet => export::write_parquet(spotify_analytics.history(), file)?,
+            }
+        }
+        Commands::Top(TopCommand {
+            kind,
+            limit,
+            by,
+            range,
+            scope,
+        }) => {
+            if by == TopBy::Count && !matches!(kind, TopKind::Track) {
+                bail!("--by count is only supported for tracks");
+            }
+            // Without a date range, username or scope the ranking can be
+            // computed in SQLite without loading the whole history.
+            let unfiltered = range.is_unbounded() && username.is_none() && scope.scope().is_none();
+            // Values are ms_played, or play counts with `--by count`.
+            let rows: Vec<(String, u64)> = if unfiltered {
+                let spotify_analytics = SpotifyAnalytics::read_only(&db, busy_timeout)?;
+                match kind {
+                    TopKind::Artist => spotify_analytics.get_top_n_artists(limit)?,
+                    TopKind::Track if by == TopBy::Count => spotify_analytics
+                        .get_top_n_tracks_by_count(limit)?
+                        .into_iter()
+                        .map(|((t, a), plays)| (format!("{t} - {a}"), plays))
+                        .collect(),
+                    TopKind::Track => spotify_analytics
+                        .get_top_n_tracks(limit)?
+                        .into_iter()
+                        .map(|((t, a), ms)| (format!("{t} - {a}"), ms))
+                        .collect(),
+                    TopKind::Album => spotify_analytics
+                        .get_top_n_albums(limit)?
+                        .into_iter()
+                        .map(|((al, a), ms)| (format!("{al} - {a}"), ms))
+                        .collect(),
+                    TopKind::Show => spotify_analytics.get_top_n_shows(limit)?,
+                }
+            } else {
+                let spotify_analytics =
+                    scope.apply(range.apply(load(&db, busy_timeout, &username)?));
+                match kind {
+                    TopKind::Artist => spotify_analytics
+                        .get_top_n_artists(limit)
+                        .into_iter()
+                        .map(|(a, ms)| (a.to_owned(), ms))
+                        .collect(),
+                    TopKind::Track if by == TopBy::Count => spotify_analytics
+                        .get_most_repeated_tracks(limit)
+                        .into_iter()
+                        .map(|((t, a), plays)| (format!("{t} - {a}"), plays))
+                        .collect(),
+                    TopKind::Track => spotify_analytics
+                        .get_top_n_tracks(limit)
+                        .into_iter()
+                        .map(|((t, a), ms)| (format!("{t} - {a}"), ms))
+                        .collect(),
+                    TopKind::Album => spotify_analytics
+                        .get_top_n_albums(limit)
+                        .into_iter()
+                        .map(|((al, a), ms)| (format!("{al} - {a}"), ms))
+                        .collect(),
+                    TopKind::Show => spotify_analytics
+                        .get_top_n_shows(limit)
+                        .into_iter()
+                        .map(|(sh, ms)| (sh.to_owned(), ms))
+                        .collect(),
+                }
+            };
+            if by == TopBy::Count {
+                let rows = rows
+                    .into_iter()
+                    .map(|(name, plays)| vec![name, plays.to_string()])
+                    .collect();
+                emit(&Table::new(&["name", "plays"], rows), output)?;
+            } else {
+                emit(&rows.into_iter().collect::<Ranking>(), output)?;
+            }
+        }
+        Commands::Wrapped(WrappedCommand { year, tz }) => {
+            // The year and its biggest day are both taken in --tz.
+            let from = tz
+                .with_ymd_and_hms(year, 1, 1, 0, 0, 0)
+                .earliest()
+                .ok_or_else(|| eyre!("invalid year {year}"))?
+                .with_timezone(&Utc);
+            let to = tz
+                .with_ymd_and_hms(year + 1, 1, 1, 0, 0, 0)
+                .earliest()
+                .ok_or_else(|| eyre!("invalid year {year}"))?
+                .with_timezone(&Utc)
+                - Duration::nanoseconds(1);
+            let spotify_analytics = load(&db, busy_timeout, &username)?.filtered(from, to);
+            let Some(&(day, day_ms)) = spotify_analytics.get_top_listening_days_in(1, &tz).first()
+            else {
+                eprintln!("No listening history for {year}. Nothing to wrap up!");
+                return Ok(());
+            };
+
+            let report = WrappedReport {
+                year,
+                top_artists: spotify_analytics.get_top_n_artists(5).into_iter().collect(),
+                top_tracks: spotify_analytics
+                    .get_top_n_tracks(5)
+                    .into_iter()
+                    .map(|((t, a), ms)| (format!("{t} - {a}"), ms))
+                    .collect(),
+                minutes_listened: spotify_analytics.total_ms_played() / 60_000,
+                unique_artists: spotify_analytics.count_unique_artists(),
+                biggest_day: day,
+                biggest_day_ms_played: day_ms,
+                night_listening_ratio: spotify_analytics.get_night_listening_ratio(&tz),
+            };
+            emit(&report, output)?;
+        }
+        Commands::When(WhenCommand { artist }) => {
+            let spotify_analytics = load(&db, busy_timeout, &username)?;
+            let Some((first, last)) = spotify_analytics.get_play_range(&artist) else {
+                eprintln!("no plays found for {artist}");
+                return Ok(());
+            };
+            let table = Table::new(
+                &["first_played", "last_played"],
+                vec![vec![first.to_string(), last.to_string()]],
+            );
+            emit(&table, output)?;
+        }
+        Commands::Query(QueryCommand { sql }) => {
+            let conn = db::get_db_read_only(&db)?;
+            let (headers, rows) = db::query_sql(&conn, &sql)?;
+            emit(&Table { headers, rows }, output)?;
+        }
+        Commands::Dedup(DedupCommand { apply }) => {
+            // Migrating first would delete the duplicates before they could
+            // be reported.
+            let mut conn = db::get_db_unmigrated(&db, busy_timeout)?;
+            if apply {
+                let n = db::delete_duplicates_sql(&mut conn)?;
+                drop(conn);
+                spotify_analytics::get_db_with_busy_timeout(&db, busy_timeout)?;
+                println!("deleted {n} duplicate rows");
+            } else {
+                let n = db::count_duplicates_sql(&conn)?;
+                println!("found {n} duplicate rows, run with --apply to delete them");
+            }
+        }
+        Commands::Reset(ResetCommand { yes }) => {
+            if !yes {
+                print!("delete all history in {}? [y/N] ", db.display());
+                io::stdout().flush()?;
+                let mut answer = String::new();
+                io::stdin().read_line(&mut answer)?;
+                if !answer.trim().eq_ignore_ascii_case("y") {
+                    println!("aborted");
+                    return Ok(());
+                }
+            }
+            let n = db::reset_db(&db, busy_timeout)?;
+            println!("deleted {n} rows");
+        }
+        Commands::Prune(PruneCommand { range, yes }) => {
+            if range.is_unbounded() {
+                bail!("prune needs --from and/or --to, use reset to delete everything");
+            }
+            let RangeArgs { from, to } = range;
+            let mut conn = spotify_analytics::get_db_with_busy_timeout(&db, busy_timeout)?;
+            if yes {
+                let n = db::delete_range_sql(&mut conn, from, to, username.as_deref())?;
+                println!("deleted {n} rows");
+            } else {
+                let n = db::count_range_sql(&conn, from, to, username.as_deref())?;
+                println!("found {n} rows in range, run with --yes to delete them");
+            }
+        }
+        Commands::Artist(ArtistCommand {
+            name,
+            trend,
+            top_tracks,
+        }) => {
+            let spotify_analytics = load(&db, busy_timeout, &username)?;
+            let Some(summary) = spotify_analytics.get_artist_summary(&name) else {
+                eprintln!("no plays found for {name}");
+                return Ok(());
+            };
+            let report = ArtistReport {
+                summary,
+                trend: trend.then(|| {
+                    spotify_analytics
+                        .get_artist_monthly_trend(&name)
+                        .into_iter()
+                        .map(|((y, m), ms)| (format!("{y}-{m:02}"), ms))
+                        .collect()
+                }),
+                top_tracks: top_tracks.map(|n| {
+                    spotify_analytics
+                        .get_top_tracks_for_artist(&name, n)
+                        .into_iter()
+                        .collect()
+                }),
+            };
+            emit(&report, output)?;
+        }
+        Commands::ByYear(ByYearCommand {}) => {
+            let spotify_analytics = load(&db, busy_timeout, &username)?;
+            emit(
+                &spotify_analytics
+                    .get_listening_by_year()
+                    .into_iter()
+                    .collect::<Series>(),
+                output,
+            )?;
+        }
+        Commands::ByMonth(ByMonthCommand {}) => {
+            let spotify_analytics = load(&db, busy_timeout, &username)?;
+            emit(
+                &spotify_analytics
+                    .get_listening_by_year_month()
+                    .into_iter()
+                    .map(|((y, m), ms)| (format!("{y}-{m:02}"), ms))
+                    .collect::<Series>(),
+                output,
+            )?;
+        }
+        Commands::ByWeekday(ByWeekdayCommand { tz }) => {
+            let spotify_analytics = load(&db, busy_timeout, &username)?;
+            emit(
+                &WEEKDAYS
+                    .into_iter()
+                    .zip(spotify_analytics.get_listening_by_weekday_in(&tz))
+                    .collect::<Series>(),
+                output,
+            )?;
+        }
+        Commands::Heatmap(HeatmapCommand { tz }) => {
+            let spotify_analytics = load(&db, busy_timeout, &username)?;
+            emit(
+                &Heatmap(spotify_analytics.get_weekday_hour_matrix_in(&tz)),
+                output,
+            )?;
+        }
+        Commands::Search(SearchCommand { query }) => {
+            let spotify_analytics = load(&db, busy_timeout, &username)?;
+            let rows = spotify_analytics
+                .search_artists(&query)
+                .into_iter()
+                .map(|a| vec![a.to_owned()])
+                .collect();
+            emit(&Table::new(&["artist"], rows), output)?;
+        }
+        Commands::Usernames(UsernamesCommand {}) => {
+            let spotify_analytics = load(&db, busy_timeout, &username)?;
+            let rows = spotify_analytics
+                .get_usernames()
+                .into_iter()
+                .map(|u| vec![u.to_owned()])
+                .collect();
+            emit(&Table::new(&["username"], rows), output)?;
+        }
+        Commands::Discoveries(DiscoveriesCommand { range }) => {
+            // Discovery dates come from the whole history, so an artist first
+            // heard before the range is not reported inside it.
+            let spotify_analytics = load(&db, busy_timeout, &username)?;
+            let rows = spotify_analytics
+                .get_artist_discovery_timeline()
+                .into_iter()
+                .filter(|(_, first_play)| range.contains(*first_play))
+                .map(|(artist, first_play)| {
+                    vec![first_play.date_naive().to_string(), artist.to_owned()]
+                })
+                .collect();
+            emit(&Table::new(&["first_played", "artist"], rows), output)?;
+        }
+        Commands::Compare(CompareCommand { range_a, range_b }) => {
+            let spotify_analytics = load(&db, busy_timeout, &username)?;
+            let comparison = spotify_analytics
+                .compare_ranges((range_a[0], range_a[1]), (range_b[0], range_b[1]));
+            emit(&comparison, output)?;
+        }
+        Commands::Payout(PayoutCommand { rate, limit }) => {
+            let spotify_analytics = load(&db, busy_timeout, &username)?;
+            let rows = spotify_analytics
+                .get_estimated_payout(rate)
+                .into_iter()
+                .take(limit)
+                .map(|(artist, usd)| vec![artist.to_owned(), format!("{usd:.2}")])
+                .collect();
+            emit(&Table::new(&["artist", "usd"], rows), output)?;
+        }
+        Commands::Plays(PlaysCommand { uri }) => {
+            let spotify_analytics = load(&db, busy_timeout, &username)?;
+            let rows = spotify_analytics
+                .get_play_events_for_uri(&uri)
+                .into_iter()
+                .map(|ts| vec![ts.to_rfc3339()])
+                .collect();
+            emit(&Table::new(&["ts"], rows), output)?;
+        }
+        Commands::OneHits(OneHitsCommand {}) => {
+            let spotify_analytics = load(&db, busy_timeout, &username)?;
+            let rows = spotify_analytics
+                .get_one_play_artists()
+                .into_iter()
+                .map(|a| vec![a.to_owned()])
+                .collect();
+            emit(&Table::new(&["artist"], rows), output)?;
+        }
+        Commands::Annotate(AnnotateCommand { label, from, to }) => {
+            if from > to {
+                bail!("--from must not be after --to");
+            }
+            let conn = spotify_analytics::get_db_with_busy_timeout(&db, busy_timeout)?;
+            db::add_annotation_sql(&conn, &label, from, to)?;
+            println!("labeled {from} to {to} as {label}");
+        }
+        Commands::ByAnnotation(ByAnnotationCommand {}) => {
+            let spotify_analytics = load(&db, busy_timeout, &username)?;
+            emit(
+                &spotify_analytics
+                    .get_listening_by_annotation()
+                    .into_iter()
+                    .collect::<Ranking>(),
+                output,
+            )?;
+        }
+        Commands::Faded(FadedCommand { days, limit }) => {
+            let spotify_analytics = load(&db, busy_timeout, &username)?;
+            let rows = spotify_analytics
+                .get_faded_artists(days, limit)
+                .into_iter()
+                .map(|a| vec![a.to_owned()])
+                .collect();
+            emit(&Table::new(&["artist"], rows), output)?;
+        }
+        #[cfg(feature = "spotify-api")]
+        Commands::FetchDurations(FetchDurationsCommand {
+            client_id,
+            client_secret,
+        }) => {
+            let creds = rspotify::Credentials::new(&client_id, &client_secret);
+            let added =
+                spotify_analytics::spotify_api::fetch_track_durations(&db, busy_timeout, creds)?;
+            println!("cached {added} track durations");
+            let spotify_analytics = load(&db, busy_timeout, &username)?;
+            let ratios = spotify_analytics.get_completion_ratio();
+            if !ratios.is_empty() {
+                let mean = ratios.iter().map(|(_, r)| r).sum::<f64>() / ratios.len() as f64;
+                println!(
+                    "average completion: {:.1}% over {} plays",
+                    mean * 100.0,
+                    ratios.len()
+                );
+            }
+        }
+        Commands::ByCountry(ByCountryCommand {}) => {
+            let spotify_analytics = load(&db, busy_timeout, &username)?;
+            emit(
+                &spotify_analytics
+                    .get_listening_by_country()
+                    .into_iter()
+                    .collect::<Ranking>(),
+                output,
+            )?;
         }
     }
 
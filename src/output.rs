@@ -0,0 +1,453 @@
+use clap::ValueEnum;
+use color_eyre::eyre::Result;
+use serde::ser::SerializeMap;
+use serde::{Serialize, Serializer};
+use serde_json::Value;
+use spotify_analytics::db::{ArtistSummary, RangeComparison};
+use spotify_analytics::report::StatsReport;
+use std::io;
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum OutputFormat {
+    Table,
+    Json,
+    Csv,
+}
+
+/// A command result that can be printed in every [`OutputFormat`].
+pub trait Render: Serialize {
+    /// Prints the human-readable form.
+    fn print_table(&self);
+
+    /// Header and rows of the CSV form. Defaults to one `field,value` row per
+    /// scalar, with nested fields named by their dotted path.
+    fn csv(&self) -> Result<(Vec<String>, Vec<Vec<String>>)> {
+        let mut rows = Vec::new();
+        flatten("", &serde_json::to_value(self)?, &mut rows);
+        Ok((vec!["field".to_owned(), "value".to_owned()], rows))
+    }
+}
+
+pub fn emit<T: Render>(value: &T, format: OutputFormat) -> Result<()> {
+    match format {
+        OutputFormat::Table => value.print_table(),
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(value)?),
+        OutputFormat::Csv => {
+            let (headers, rows) = value.csv()?;
+            let mut w = csv::Writer::from_writer(io::stdout().lock());
+            w.write_record(&headers)?;
+            for row in rows {
+                w.write_record(&row)?;
+            }
+            w.flush()?;
+        }
+    }
+    Ok(())
+}
+
+fn flatten(path: &str, value: &Value, rows: &mut Vec<Vec<String>>) {
+    let join = |key: &dyn std::fmt::Display| {
+        if path.is_empty() {
+            key.to_string()
+        } else {
+            format!("{path}.{key}")
+        }
+    };
+    match value {
+        Value::Object(map) => {
+            for (k, v) in map {
+                flatten(&join(k), v, rows);
+            }
+        }
+        Value::Array(items) => {
+            for (i, v) in items.iter().enumerate() {
+                flatten(&join(&i), v, rows);
+            }
+        }
+        Value::Null => rows.push(vec![path.to_owned(), String::new()]),
+        Value::String(s) => rows.push(vec![path.to_owned(), s.clone()]),
+        v => rows.push(vec![path.to_owned(), v.to_string()]),
+    }
+}
+
+pub fn format_ms(ms: u64) -> String {
+    let minutes = ms / 60_000;
+    format!("{}h {:02}m", minutes / 60, minutes % 60)
+}
+
+pub fn format_hours(ms: u64) -> String {
+    format!("{:.1}h", ms as f64 / 3_600_000.0)
+}
+
+/// Like [`format_hours`], with an explicit sign.
+pub fn format_hours_delta(ms: i64) -> String {
+    format!("{:+.1}h", ms as f64 / 3_600_000.0)
+}
+
+#[derive(Debug, Serialize)]
+pub struct RankingRow {
+    pub name: String,
+    pub ms_played: u64,
+}
+
+/// Names ordered by listening time, such as a top-N list.
+#[derive(Debug, Serialize)]
+#[serde(transparent)]
+pub struct Ranking(pub Vec<RankingRow>);
+
+impl<S: Into<String>> FromIterator<(S, u64)> for Ranking {
+    fn from_iter<I: IntoIterator<Item = (S, u64)>>(iter: I) -> Self {
+        Self(
+            iter.into_iter()
+                .map(|(name, ms_played)| RankingRow {
+                    name: name.into(),
+                    ms_played,
+                })
+                .collect(),
+        )
+    }
+}
+
+impl Render for Ranking {
+    fn print_table(&self) {
+        let width = self
+            .0
+            .iter()
+            .map(|r| r.name.chars().count())
+            .max()
+            .unwrap_or(0);
+        for (i, r) in self.0.iter().enumerate() {
+            println!(
+                "{:>3}. {:<width$}  {:>8}",
+                i + 1,
+                r.name,
+                format_hours(r.ms_played)
+            );
+        }
+    }
+
+    fn csv(&self) -> Result<(Vec<String>, Vec<Vec<String>>)> {
+        let rows = self
+            .0
+            .iter()
+            .map(|r| vec![r.name.clone(), r.ms_played.to_string()])
+            .collect();
+        Ok((vec!["name".to_owned(), "ms_played".to_owned()], rows))
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct SeriesRow {
+    pub period: String,
+    pub ms_played: u64,
+}
+
+/// Listening time per period in chronological order, drawn as bars.
+#[derive(Debug, Serialize)]
+#[serde(transparent)]
+pub struct Series(pub Vec<SeriesRow>);
+
+impl<K: ToString> FromIterator<(K, u64)> for Series {
+    fn from_iter<I: IntoIterator<Item = (K, u64)>>(iter: I) -> Self {
+        Self(
+            iter.into_iter()
+                .map(|(period, ms_played)| SeriesRow {
+                    period: period.to_string(),
+                    ms_played,
+                })
+                .collect(),
+        )
+    }
+}
+
+impl Render for Series {
+    fn print_table(&self) {
+        const BAR_WIDTH: u64 = 40;
+        let max = self.0.iter().map(|r| r.ms_played).max().unwrap_or(0).max(1);
+        for r in &self.0 {
+            let bar = "#".repeat((r.ms_played * BAR_WIDTH / max) as usize);
+            println!("{}  {:>8}  {bar}", r.period, format_hours(r.ms_played));
+        }
+    }
+
+    fn csv(&self) -> Result<(Vec<String>, Vec<Vec<String>>)> {
+        let rows = self
+            .0
+            .iter()
+            .map(|r| vec![r.period.clone(), r.ms_played.to_string()])
+            .collect();
+        Ok((vec!["period".to_owned(), "ms_played".to_owned()], rows))
+    }
+}
+
+/// Plain rows of text cells, serialized to JSON as one object per row.
+#[derive(Debug)]
+pub struct Table {
+    pub headers: Vec<String>,
+    pub rows: Vec<Vec<String>>,
+}
+
+impl Table {
+    pub fn new(headers: &[&str], rows: Vec<Vec<String>>) -> Self {
+        Self {
+            headers: headers.iter().map(|h| h.to_string()).collect(),
+            rows,
+        }
+    }
+}
+
+impl Serialize for Table {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        struct Row<'a>(&'a [String], &'a [String]);
+
+        impl Serialize for Row<'_> {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                let mut map = serializer.serialize_map(Some(self.0.len()))?;
+                for (k, v) in self.0.iter().zip(self.1) {
+                    map.serialize_entry(k, v)?;
+                }
+                map.end()
+            }
+        }
+
+        serializer.collect_seq(self.rows.iter().map(|r| Row(&self.headers, r)))
+    }
+}
+
+impl Render for Table {
+    fn print_table(&self) {
+        let mut widths: Vec<usize> = self.headers.iter().map(|h| h.chars().count()).collect();
+        for row in &self.rows {
+            for (w, cell) in widths.iter_mut().zip(row) {
+                *w = (*w).max(cell.chars().count());
+            }
+        }
+        let line = |cells: &[String]| {
+            cells
+                .iter()
+                .zip(&widths)
+                .map(|(cell, w)| format!("{cell:<w$}"))
+                .collect::<Vec<_>>()
+                .join("  ")
+                .trim_end()
+                .to_owned()
+        };
+        println!("{}", line(&self.headers));
+        println!(
+            "{}",
+            widths
+                .iter()
+                .map(|w| "-".repeat(*w))
+                .collect::<Vec<_>>()
+                .join("  ")
+        );
+        for row in &self.rows {
+            println!("{}", line(row));
+        }
+    }
+
+    fn csv(&self) -> Result<(Vec<String>, Vec<Vec<String>>)> {
+        Ok((self.headers.clone(), self.rows.clone()))
+    }
+}
+
+impl Render for StatsReport {
+    fn print_table(&self) {
+        let (Some(first_play), Some(last_play)) = (self.first_play, self.last_play) else {
+            println!("no data");
+            return;
+        };
+        println!("total plays:     {}", self.total_plays);
+        println!("listening time:  {}", format_ms(self.total_ms_played));
+        println!(
+            "unique artists:  {} ({} played only once)",
+            self.unique_artists, self.one_play_artists
+        );
+        println!("unique tracks:   {}", self.unique_tracks);
+        println!("unique albums:   {}", self.unique_albums);
+        println!("unique shows:    {}", self.unique_shows);
+        println!("date range:      {} to {}", first_play, last_play);
+        println!(
+            "plays per day:   {:.1} avg, {} max",
+            self.avg_plays_per_day, self.max_plays_per_day
+        );
+        println!();
+        println!("completion breakdown");
+        println!("  completed:     {}", self.completion.completed);
+        println!("  skipped:       {}", self.completion.skipped);
+        println!("  other:         {}", self.completion.other);
+        println!();
+        println!("weekday vs weekend");
+        println!("  weekday:       {}", format_ms(self.weekday_ms_played));
+        println!("  weekend:       {}", format_ms(self.weekend_ms_played));
+        println!();
+        println!("offline vs online");
+        println!("  offline:       {}", format_ms(self.offline_ms_played));
+        println!("  online:        {}", format_ms(self.online_ms_played));
+        println!();
+        println!("shuffle:         {:.1}%", self.shuffle_ratio * 100.0);
+        println!(
+            "skipped:         {:.1}% ({} of {} plays with a skip flag)",
+            self.skip_ratio * 100.0,
+            self.skipped_plays,
+            self.skip_flagged_plays
+        );
+        if self.incognito_plays > 0 {
+            println!();
+            println!("incognito");
+            println!("  plays:         {}", self.incognito_plays);
+            println!("  listening:     {}", format_ms(self.incognito_ms_played));
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ArtistReport {
+    #[serde(flatten)]
+    pub summary: ArtistSummary,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trend: Option<Series>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_tracks: Option<Ranking>,
+}
+
+impl Render for ArtistReport {
+    fn print_table(&self) {
+        let summary = &self.summary;
+        println!("{}", summary.name);
+        println!("  plays:           {}", summary.play_count);
+        println!("  listening time:  {}", format_ms(summary.ms_played));
+        println!("  distinct tracks: {}", summary.distinct_tracks);
+        println!("  first played:    {}", summary.first_play);
+        println!("  last played:     {}", summary.last_play);
+        if let Some(top_tracks) = &self.top_tracks {
+            println!();
+            top_tracks.print_table();
+        }
+        if let Some(trend) = &self.trend {
+            println!();
+            trend.print_table();
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct WrappedReport {
+    pub year: i32,
+    pub top_artists: Ranking,
+    pub top_tracks: Ranking,
+    pub minutes_listened: u64,
+    pub unique_artists: usize,
+    pub biggest_day: chrono::NaiveDate,
+    pub biggest_day_ms_played: u64,
+    pub night_listening_ratio: f64,
+}
+
+impl Render for WrappedReport {
+    fn print_table(&self) {
+        println!("Your {} Wrapped", self.year);
+        println!();
+        println!("Top artists");
+        self.top_artists.print_table();
+        println!();
+        println!("Top tracks");
+        self.top_tracks.print_table();
+        println!();
+        println!("Minutes listened:  {}", self.minutes_listened);
+        println!("Unique artists:    {}", self.unique_artists);
+        println!(
+            "Biggest day:       {} ({})",
+            self.biggest_day,
+            format_ms(self.biggest_day_ms_played)
+        );
+        println!(
+            "Night owl score:   {:.1}% of listening between midnight and 5am",
+            self.night_listening_ratio * 100.0
+        );
+    }
+}
+
+/// Weekday labels, from Monday.
+pub const WEEKDAYS: [&str; 7] = ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+
+/// ms_played per `[weekday][hour]`, weekdays from Monday.
+#[derive(Debug, Serialize)]
+#[serde(transparent)]
+pub struct Heatmap(pub [[u64; 24]; 7]);
+
+impl Heatmap {
+    fn table(&self, cell: impl Fn(u64) -> String) -> Table {
+        let mut headers = vec!["weekday".to_owned()];
+        headers.extend((0..24).map(|h| h.to_string()));
+        let rows = WEEKDAYS
+            .iter()
+            .zip(&self.0)
+            .map(|(day, hours)| {
+                let mut row = vec![day.to_string()];
+                row.extend(hours.iter().map(|ms| cell(*ms)));
+                row
+            })
+            .collect();
+        Table { headers, rows }
+    }
+}
+
+impl Render for Heatmap {
+    fn print_table(&self) {
+        self.table(|ms| (ms / 60_000).to_string()).print_table();
+    }
+
+    fn csv(&self) -> Result<(Vec<String>, Vec<Vec<String>>)> {
+        self.table(|ms| ms.to_string()).csv()
+    }
+}
+
+impl Render for RangeComparison {
+    fn print_table(&self) {
+        comparison_table(self).print_table();
+    }
+
+    fn csv(&self) -> Result<(Vec<String>, Vec<Vec<String>>)> {
+        comparison_table(self).csv()
+    }
+}
+
+fn comparison_table(cmp: &RangeComparison) -> Table {
+    let (a, b) = (&cmp.a, &cmp.b);
+    let top_artist = |s: &Option<String>| s.clone().unwrap_or_default();
+    Table::new(
+        &["", "a", "b", "change"],
+        vec![
+            vec![
+                "range".to_owned(),
+                format!("{} to {}", a.from.date_naive(), a.to.date_naive()),
+                format!("{} to {}", b.from.date_naive(), b.to.date_naive()),
+                String::new(),
+            ],
+            vec![
+                "listening time".to_owned(),
+                format_hours(a.ms_played),
+                format_hours(b.ms_played),
+                format_hours_delta(cmp.ms_played_delta),
+            ],
+            vec![
+                "top artist".to_owned(),
+                top_artist(&a.top_artist),
+                top_artist(&b.top_artist),
+                String::new(),
+            ],
+            vec![
+                "unique artists".to_owned(),
+                a.unique_artists.to_string(),
+                b.unique_artists.to_string(),
+                format!("{:+}", cmp.unique_artists_delta),
+            ],
+            vec![
+                "unique tracks".to_owned(),
+                a.unique_tracks.to_string(),
+                b.unique_tracks.to_string(),
+                format!("{:+}", cmp.unique_tracks_delta),
+            ],
+        ],
+    )
+}
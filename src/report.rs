@@ -0,0 +1,112 @@
+use crate::SpotifyAnalytics;
+use chrono::{DateTime, TimeZone, Utc};
+use serde::Serialize;
+
+/// Summary of a listening history, shaped for `stats --output json`.
+#[derive(Debug, Serialize)]
+pub struct StatsReport {
+    pub total_plays: usize,
+    pub total_ms_played: u64,
+    pub unique_artists: usize,
+    pub one_play_artists: usize,
+    pub unique_tracks: usize,
+    pub unique_albums: usize,
+    pub unique_shows: usize,
+    pub first_play: Option<DateTime<Utc>>,
+    pub last_play: Option<DateTime<Utc>>,
+    pub avg_plays_per_day: f64,
+    pub max_plays_per_day: u64,
+    pub completion: CompletionBreakdown,
+    pub weekday_ms_played: u64,
+    pub weekend_ms_played: u64,
+    pub offline_ms_played: u64,
+    pub online_ms_played: u64,
+    pub incognito_plays: u64,
+    pub incognito_ms_played: u64,
+    pub shuffle_ratio: f64,
+    pub skipped_plays: u64,
+    /// Plays with a non-null `skipped` flag, the sample `skip_ratio` is
+    /// based on.
+    pub skip_flagged_plays: u64,
+    pub skip_ratio: f64,
+    pub top_artists: Vec<ArtistTotal>,
+    pub top_tracks: Vec<TrackTotal>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CompletionBreakdown {
+    pub completed: u64,
+    pub skipped: u64,
+    pub other: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ArtistTotal {
+    pub artist: String,
+    pub ms_played: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TrackTotal {
+    pub track: String,
+    pub artist: String,
+    pub ms_played: u64,
+}
+
+impl StatsReport {
+    /// Builds the report, bucketing day and time-of-week figures in `tz`.
+    pub fn new<Tz: TimeZone>(analytics: &SpotifyAnalytics, top_n: usize, tz: &Tz) -> Self {
+        let date_range = analytics.date_range();
+        let (completed, skipped, other) = analytics.get_completion_stats();
+        let (weekday_ms_played, weekend_ms_played) = analytics.get_weekday_weekend_split_in(tz);
+        let (avg_plays_per_day, max_plays_per_day) = analytics.get_daily_play_stats_in(tz);
+        let (offline_ms_played, online_ms_played) = analytics.get_offline_split();
+        let (incognito_plays, incognito_ms_played) = analytics.get_incognito_stats();
+        let (skipped_plays, skip_flagged_plays, skip_ratio) = analytics.get_overall_skip_ratio();
+        Self {
+            total_plays: analytics.total_plays(),
+            total_ms_played: analytics.total_ms_played(),
+            unique_artists: analytics.count_unique_artists(),
+            one_play_artists: analytics.get_one_play_artists().len(),
+            unique_tracks: analytics.count_unique_tracks(),
+            unique_albums: analytics.count_unique_albums(),
+            unique_shows: analytics.count_unique_shows(),
+            first_play: date_range.map(|(min_ts, _)| min_ts),
+            last_play: date_range.map(|(_, max_ts)| max_ts),
+            avg_plays_per_day,
+            max_plays_per_day,
+            completion: CompletionBreakdown {
+                completed,
+                skipped,
+                other,
+            },
+            weekday_ms_played,
+            weekend_ms_played,
+            offline_ms_played,
+            online_ms_played,
+            incognito_plays,
+            incognito_ms_played,
+            shuffle_ratio: analytics.get_shuffle_percentage(),
+            skipped_plays,
+            skip_flagged_plays,
+            skip_ratio,
+            top_artists: analytics
+                .get_top_n_artists(top_n)
+                .into_iter()
+                .map(|(artist, ms_played)| ArtistTotal {
+                    artist: artist.to_owned(),
+                    ms_played,
+                })
+                .collect(),
+            top_tracks: analytics
+                .get_top_n_tracks(top_n)
+                .into_iter()
+                .map(|((track, artist), ms_played)| TrackTotal {
+                    track: track.to_owned(),
+                    artist: artist.to_owned(),
+                    ms_played,
+                })
+                .collect(),
+        }
+    }
+}
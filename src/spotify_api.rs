@@ -0,0 +1,138 @@
+//! Track duration lookups through the Spotify Web API, enabled by the
+//! `spotify-api` feature.
+
+use crate::db::{get_db_with_busy_timeout, into_locked};
+use crate::error::Result;
+use rspotify::http::HttpError;
+use rspotify::http::Query;
+use rspotify::model::{FullTrack, TrackId};
+use rspotify::prelude::*;
+use rspotify::{ClientCredsSpotify, ClientError, ClientResult, Credentials};
+use serde::Deserialize;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// The tracks endpoint accepts at most 50 ids per request.
+const MAX_IDS_PER_REQUEST: usize = 50;
+
+/// Rate-limited requests are retried this many times before giving up.
+const MAX_RETRIES: u32 = 5;
+
+/// The tracks endpoint answers `null` for ids it doesn't know, which
+/// [`FullTracks`](rspotify::model::FullTracks) can't hold.
+#[derive(Deserialize)]
+struct Tracks {
+    tracks: Vec<Option<FullTrack>>,
+}
+
+/// Looks up the duration of every track in the history at `db_path` that is
+/// not cached yet and stores it in the `track_duration` table. Returns how
+/// many durations were added. Tracks the API doesn't know, and URIs that
+/// aren't track URIs, go to `track_unavailable` so later runs skip them.
+pub fn fetch_track_durations(
+    db_path: &Path,
+    busy_timeout: Duration,
+    creds: Credentials,
+) -> Result<usize> {
+    let spotify = ClientCredsSpotify::new(creds);
+    spotify.request_token()?;
+
+    let mut conn = get_db_with_busy_timeout(db_path, busy_timeout)?;
+    let uris: Vec<String> = conn
+        .prepare(
+            "SELECT DISTINCT spotify_track_uri FROM spotify_history
+            WHERE spotify_track_uri IS NOT NULL
+              AND spotify_track_uri NOT IN (SELECT spotify_track_uri FROM track_duration)
+              AND spotify_track_uri NOT IN (SELECT spotify_track_uri FROM track_unavailable)",
+        )?
+        .query_map([], |r| r.get(0))?
+        .collect::<Result<_, rusqlite::Error>>()?;
+    info!(tracks = uris.len(), "looking up track durations");
+
+    let locked = |e: rusqlite::Error| into_locked(e.into(), db_path, busy_timeout);
+    let mut added = 0;
+    for chunk in uris.chunks(MAX_IDS_PER_REQUEST) {
+        let mut unavailable = Vec::new();
+        let mut ids = Vec::new();
+        for uri in chunk {
+            match TrackId::from_uri(uri) {
+                Ok(id) => ids.push((uri, id)),
+                Err(err) => {
+                    warn!(%err, uri, "skipping invalid track uri");
+                    unavailable.push(uri);
+                }
+            }
+        }
+        let tracks = if ids.is_empty() {
+            Vec::new()
+        } else {
+            let joined = ids
+                .iter()
+                .map(|(_, id)| id.id())
+                .collect::<Vec<_>>()
+                .join(",");
+            let body =
+                with_retry(|| spotify.api_get(&format!("tracks/?ids={joined}"), &Query::new()))?;
+            serde_json::from_str::<Tracks>(&body)?.tracks
+        };
+
+        // Each batch is committed on its own so a later failure keeps the
+        // durations fetched so far. The response lists tracks in the order
+        // they were asked for.
+        let tx = conn.transaction().map_err(locked)?;
+        for ((uri, _), track) in ids.iter().zip(tracks) {
+            match track {
+                Some(track) => {
+                    tx.execute(
+                        "INSERT OR REPLACE INTO track_duration VALUES (?1, ?2)",
+                        (uri, track.duration.num_milliseconds()),
+                    )
+                    .map_err(locked)?;
+                    added += 1;
+                }
+                None => {
+                    warn!(uri, "track is unavailable");
+                    unavailable.push(uri);
+                }
+            }
+        }
+        for uri in unavailable {
+            tx.execute("INSERT OR IGNORE INTO track_unavailable VALUES (?1)", [uri])
+                .map_err(locked)?;
+        }
+        tx.commit().map_err(locked)?;
+    }
+    Ok(added)
+}
+
+/// Runs `request`, sleeping for the `Retry-After` period whenever Spotify
+/// answers with 429 Too Many Requests.
+fn with_retry<T>(request: impl Fn() -> ClientResult<T>) -> Result<T> {
+    let mut retries = 0;
+    loop {
+        let err = match request() {
+            Ok(r) => return Ok(r),
+            Err(err) => err,
+        };
+        let retry_after = match &err {
+            ClientError::Http(http) => match http.as_ref() {
+                HttpError::StatusCode(response) if response.status() == 429 => response
+                    .header("Retry-After")
+                    .and_then(|s| s.parse().ok())
+                    .or(Some(1)),
+                _ => None,
+            },
+            _ => None,
+        };
+        match retry_after {
+            Some(secs) if retries < MAX_RETRIES => {
+                warn!(secs, "rate limited by the Spotify API, retrying");
+                thread::sleep(Duration::from_secs(secs));
+                retries += 1;
+            }
+            _ => return Err(err.into()),
+        }
+    }
+}
@@ -0,0 +1,103 @@
+//! Peak memory of streaming history imports. This lives in its own test
+//! binary because it installs a counting global allocator, and holds a single
+//! test so nothing else allocates while it measures.
+
+use spotify_analytics::{SpotifyAnalytics, SpotifyHistoryEntry};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+struct PeakAlloc;
+
+static TRACKING: AtomicBool = AtomicBool::new(false);
+static LIVE: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for PeakAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if TRACKING.load(Ordering::Relaxed) {
+            let live = LIVE.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+            PEAK.fetch_max(live, Ordering::Relaxed);
+        }
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if TRACKING.load(Ordering::Relaxed) {
+            let _ = LIVE.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |live| {
+                Some(live.saturating_sub(layout.size()))
+            });
+        }
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOC: PeakAlloc = PeakAlloc;
+
+fn peak_alloc<T>(f: impl FnOnce() -> T) -> (T, usize) {
+    LIVE.store(0, Ordering::Relaxed);
+    PEAK.store(0, Ordering::Relaxed);
+    TRACKING.store(true, Ordering::Relaxed);
+    let value = f();
+    TRACKING.store(false, Ordering::Relaxed);
+    (value, PEAK.load(Ordering::Relaxed))
+}
+
+fn entry(i: u64) -> SpotifyHistoryEntry {
+    SpotifyHistoryEntry {
+        ts: "2020-01-01T10:00:00Z".parse().unwrap(),
+        username: Some("user".to_owned()),
+        platform: None,
+        ms_played: i,
+        conn_country: None,
+        ip_addr_decrypted: None,
+        user_agent_decrypted: None,
+        master_metadata_track_name: Some(format!("t{i}")),
+        master_metadata_album_artist_name: Some("a".to_owned()),
+        master_metadata_album_album_name: None,
+        spotify_track_uri: Some(format!("spotify:track:t{i}")),
+        episode_name: None,
+        episode_show_name: None,
+        spotify_episode_uri: None,
+        reason_start: None,
+        reason_end: None,
+        shuffle: None,
+        skipped: None,
+        offline: None,
+        offline_timestamp: None,
+        incognito_mode: None,
+    }
+}
+
+#[test]
+fn streaming_deserialize_peaks_below_reading_the_whole_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let file = dir.path().join("large.json");
+    let entries: Vec<SpotifyHistoryEntry> = (0..20_000).map(entry).collect();
+    fs::write(&file, serde_json::to_vec(&entries).unwrap()).unwrap();
+    let file_len = fs::metadata(&file).unwrap().len() as usize;
+    let mut analytics = SpotifyAnalytics::new(dir.path().join("history.db")).unwrap();
+
+    let (slurped, slurped_peak) = peak_alloc(|| {
+        serde_json::from_slice::<Vec<SpotifyHistoryEntry>>(&fs::read(&file).unwrap()).unwrap()
+    });
+    drop(slurped);
+    let ((), streamed_peak) = peak_alloc(|| {
+        analytics
+            .deserialize_extended_streaming_history_json_reader(BufReader::new(
+                File::open(&file).unwrap(),
+            ))
+            .unwrap()
+    });
+    assert_eq!(analytics.incoming().len(), entries.len());
+    assert_eq!(analytics.incoming()[19_999].ms_played, 19_999);
+
+    // Streaming never holds the raw JSON, which reading the file first keeps
+    // alive next to every parsed entry.
+    assert!(
+        streamed_peak + file_len / 2 < slurped_peak,
+        "streamed {streamed_peak} bytes, slurped {slurped_peak} bytes, file is {file_len} bytes"
+    );
+}